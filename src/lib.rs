@@ -0,0 +1,4793 @@
+//! A very simple web server for hosting html files.
+//!
+//! The `simplewebserver_rs` binary is a thin wrapper around [`run`]. Embedding the server takes the same options,
+//! but [`Config::from_cli`] and [`serve`] return errors instead of exiting:
+//!
+//! ```no_run
+//! use clap::Parser;
+//!
+//! let cli = simplewebserver_rs::Cli::parse_from(["simplewebserver_rs", "--root", "site"]);
+//! let config = simplewebserver_rs::Config::from_cli(&cli)?;
+//! simplewebserver_rs::serve(config)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+// Only use on nightly
+#![cfg_attr(on_nightly, feature(normalize_lexically))]
+#![deny(clippy::all)]
+#![deny(clippy::pedantic)]
+#![deny(clippy::nursery)]
+#![deny(clippy::cargo)]
+// Restrictions
+#![deny(clippy::allow_attributes)]
+#![deny(clippy::allow_attributes_without_reason)]
+#![deny(clippy::cfg_not_test)]
+#![deny(clippy::unwrap_used)]
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use regex::Regex;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use simplelog::*;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::io::BufReader;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::num::NonZero;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf, absolute};
+use std::process::exit;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, fs, fs::File, io, thread};
+use time::format_description::BorrowedFormatItem;
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "Needed for the CLI. Cannot be refactored into a state machine."
+)]
+pub struct Cli {
+    /// Bind IP Address
+    #[arg(default_value = "127.0.0.1", env = "BIND_ADDR")]
+    address: String,
+    /// Bind Port
+    #[arg(default_value_t = 8080, env = "PORT")]
+    port: u16,
+    #[arg(
+        short = 'q',
+        long,
+        default_value_t = false,
+        help = "Disable logging. (Log files are still used if `--enablelogfiles` is passed)",
+        conflicts_with = "verbose"
+    )]
+    quiet: bool,
+    #[arg(
+        short = 'v',
+        long,
+        default_value_t = false,
+        help = "Use verbose output",
+        conflicts_with = "quiet"
+    )]
+    verbose: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Use log files in addition to logging on stdout/err"
+    )]
+    enablelogfiles: bool,
+    #[arg(
+        short = 'r',
+        long,
+        default_value_t = 120,
+        help = "Maximum requests per minute before rate-limiting. 0 to disable"
+    )]
+    ratelimit: u16,
+    #[arg(
+        short = 'd',
+        long,
+        default_value_t = 180,
+        help = "Timeout in seconds after exceeding ratelimit"
+    )]
+    timeout: u32,
+    #[arg(
+        long,
+        conflicts_with = "timeout",
+        help = "Timeout in milliseconds after exceeding ratelimit, for finer control than --timeout"
+    )]
+    timeout_ms: Option<u64>,
+    #[arg(
+        long,
+        default_value_t = 32,
+        value_parser = clap::value_parser!(u8).range(0..=32),
+        help = "Rate-limit IPv4 clients together by this prefix length"
+    )]
+    ratelimit_ipv4_prefix: u8,
+    #[arg(
+        long,
+        default_value_t = 64,
+        value_parser = clap::value_parser!(u8).range(0..=128),
+        help = "Rate-limit IPv6 clients together by this prefix length. A single client usually controls a whole /64"
+    )]
+    ratelimit_ipv6_prefix: u8,
+    #[arg(
+        short = 'b',
+        long,
+        help = "Files to blacklist from serving. (Defaults to log files)"
+    )]
+    blacklist: Option<Vec<String>>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Indicates that the program is being run in test mode. (You don't need this for normal invocation)"
+    )]
+    testing: bool,
+    #[arg(
+        long,
+        visible_alias = "dry-run",
+        help = "Check the configuration, including that the addresses can be bound, then exit without serving anything"
+    )]
+    check: bool,
+    #[arg(
+        long,
+        visible_alias = "single-threaded",
+        default_value_t = false,
+        help = "Runs a single-threaded server (I don't know why you would want this but it's an option)"
+    )]
+    singlethreaded: bool,
+    // Only available on nightly
+    #[cfg(on_nightly)]
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Allow serving symlinks that point out of the base directory"
+    )]
+    allow_external_symlinks: bool,
+    #[arg(
+        short = 'w',
+        long,
+        default_value = ".",
+        help = "Directory to serve files from"
+    )]
+    root: PathBuf,
+    #[arg(
+        long,
+        conflicts_with = "root",
+        help = "Serve just this file, whatever path is requested"
+    )]
+    file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "HOST=DIR",
+        help = "Serve requests for HOST from DIR instead of the web root. Can be given multiple times"
+    )]
+    vhost: Vec<VirtualHost>,
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "vhost",
+        help = "Answer requests for hosts without a --vhost with a 404 instead of serving the web root"
+    )]
+    strict_vhosts: bool,
+    #[arg(
+        long,
+        value_name = "PATH=URL",
+        help = "Permanently redirect (301) requests for PATH to URL. Can be given multiple times"
+    )]
+    redirect: Vec<Redirect>,
+    #[arg(
+        long,
+        value_name = "PATH=URL",
+        help = "Like --redirect, but temporary (302), so browsers keep asking for PATH"
+    )]
+    temporary_redirect: Vec<Redirect>,
+    #[arg(
+        long,
+        default_value_t = default_workers(),
+        help = "Number of worker threads handling connections. Ignored in single-threaded mode"
+    )]
+    workers: NonZero<usize>,
+    #[arg(
+        long,
+        requires = "key",
+        help = "PEM certificate chain to serve HTTPS with. Requires --key"
+    )]
+    cert: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "cert",
+        help = "PEM private key for the certificate given with --cert"
+    )]
+    key: Option<PathBuf>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "text/html,text/css,text/javascript,application/json,text/plain,application/xml,text/csv,text/markdown,image/svg+xml",
+        help = "Comma-separated MIME types to gzip for clients that accept it"
+    )]
+    gzip_types: Vec<String>,
+    #[arg(
+        long,
+        default_value_t = 1024,
+        help = "Files smaller than this many bytes are never gzipped"
+    )]
+    gzip_min_size: u64,
+    #[arg(
+        long,
+        default_value = "utf-8",
+        help = "Charset added to the Content-Type of text files. Empty to leave it out"
+    )]
+    default_charset: String,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Let browsers cache files for this many seconds. 0 leaves caching up to them"
+    )]
+    cache_max_age: u64,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        value_parser = Regex::new,
+        help = "Let browsers cache files whose names match this for a year without checking back, like app.3f9a2c.js with \\.[0-9a-f]{6,}\\."
+    )]
+    immutable_pattern: Option<Regex>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Return 404 for directories instead of listing their contents"
+    )]
+    no_dir_listing: bool,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "index.html,index.htm",
+        help = "Comma-separated file names to serve for a directory, in order of preference. The Accept header can pick another one, like index.json for JSON clients"
+    )]
+    index: Vec<String>,
+    #[arg(
+        long,
+        value_name = "USER:PASSWORD",
+        value_parser = parse_credentials,
+        help = "Require HTTP basic authentication with these credentials"
+    )]
+    auth: Option<String>,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        help = "Only accept connections from this network. Can be given multiple times"
+    )]
+    allow: Vec<IpNet>,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        help = "Refuse connections from this network, even if it is allowed. Can be given multiple times"
+    )]
+    deny: Vec<IpNet>,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        help = "Take the client address from X-Forwarded-For on connections from this reverse proxy network. Can be given multiple times"
+    )]
+    trusted_proxy: Vec<IpNet>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Don't advertise the server name and version in responses"
+    )]
+    no_server_header: bool,
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "Seconds to keep an idle connection open for more requests. 0 to close after every request"
+    )]
+    keep_alive_timeout: u64,
+    #[arg(
+        long,
+        visible_alias = "follow-request-limit",
+        default_value_t = 100,
+        help = "Close a connection after serving this many requests on it, so one client can't hold on to a worker. 0 for no limit"
+    )]
+    max_keep_alive_requests: u32,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = AccessLogFormat::None,
+        help = "Format of the access log written to --access-log"
+    )]
+    access_log_format: AccessLogFormat,
+    #[arg(
+        long,
+        default_value = "access.log",
+        help = "File to write the access log to. Only used with --access-log-format"
+    )]
+    access_log: PathBuf,
+    #[arg(
+        long,
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Seconds a client gets to send the headers of a request before getting a 408"
+    )]
+    header_timeout: u64,
+    #[arg(
+        long,
+        default_value_t = DEFAULT_MAX_HEADER_BYTES,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1024..),
+        help = "Largest request header block accepted, in bytes. Bigger ones get a 431"
+    )]
+    max_header_bytes: usize,
+    #[arg(
+        long,
+        default_value_t = DEFAULT_MAX_URI_LENGTH,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Longest request target (path and query) accepted, in bytes. Longer ones get a 414"
+    )]
+    max_uri_length: usize,
+    #[arg(
+        long,
+        help = "Address and port to listen on, like 0.0.0.0:8080 or [::]:8080. Can be repeated. Overrides the positional address and port"
+    )]
+    bind: Vec<SocketAddr>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "bind",
+        help = "Listen on this Unix domain socket instead of a TCP port. Its clients count as 127.0.0.1 and aren't rate-limited by address"
+    )]
+    unix_socket: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = DEFAULT_BACKLOG,
+        value_parser = clap::value_parser!(i32).range(1..),
+        help = "How many connections the kernel queues up before the server gets to accept them. Capped by the system (net.core.somaxconn on Linux)"
+    )]
+    backlog: i32,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Send small responses right away instead of waiting to batch them up (disables Nagle's algorithm)"
+    )]
+    tcp_nodelay: bool,
+    #[arg(
+        long,
+        value_name = "RATE",
+        value_parser = parse_rate,
+        help = "Bytes per second files are sent to each connection at, like 500K or 1M"
+    )]
+    max_rate: Option<u64>,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Maximum number of open connections before new ones get a 503. 0 to disable"
+    )]
+    max_connections: usize,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Maximum number of open connections from one client IP before its new ones get a 503. Trusted proxies are exempt. 0 to disable"
+    )]
+    max_connections_per_ip: usize,
+    #[arg(
+        long,
+        default_value = "/healthz",
+        help = "Path that always answers 200 for load balancer health checks. Empty to disable"
+    )]
+    health_path: String,
+    #[arg(
+        long,
+        help = "Origin allowed to fetch files from other sites, or * for any. Can be repeated. Enables CORS"
+    )]
+    cors_origin: Vec<String>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "GET,HEAD,OPTIONS",
+        requires = "cors_origin",
+        help = "Methods allowed in CORS requests"
+    )]
+    cors_methods: Vec<String>,
+    #[arg(
+        long,
+        help = "TOML file to read options from, like `root = \"public\"`. Options given on the command line win"
+    )]
+    config: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Refuse to serve anything reached through a symlink. By default symlinks are followed, as long as they stay inside the web root"
+    )]
+    no_follow_symlinks: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Serve and list files and directories starting with a dot, like .git or .env. By default they 404, except for .well-known"
+    )]
+    serve_hidden_files: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Refuse to start if a web root is world-writable instead of just warning about it (Unix only)"
+    )]
+    strict_permissions: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Send error responses without a body instead of the status code. Custom error pages are still used"
+    )]
+    quiet_errors: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Log every request at info level along with its Referer and User-Agent. Noisy, and it records more about your visitors"
+    )]
+    verbose_access: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Mask client IPs in logs down to their /24 (IPv4) or /48 (IPv6). Rate limiting still uses the full address"
+    )]
+    log_ip_privacy: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ErrorFormat::Text,
+        help = "Format of the body of 429 and 503 responses. Clients asking for JSON with their Accept header get it either way"
+    )]
+    error_format: ErrorFormat,
+    #[arg(
+        long,
+        default_value = "SimpleWebServer.log",
+        help = "Where to write the log with --enablelogfiles"
+    )]
+    logfile: PathBuf,
+    #[arg(
+        long,
+        default_value = "SimpleWebServer-FULL.log",
+        help = "Where to write the full (trace level) log with --enablelogfiles"
+    )]
+    full_logfile: PathBuf,
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "enablelogfiles",
+        help = "Clear the log files on startup instead of adding to them"
+    )]
+    truncate_logs: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Show the size and modification time of files in directory listings",
+        conflicts_with = "no_dir_listing"
+    )]
+    listing_details: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Always list directories as JSON. Clients asking for JSON with their Accept header get it either way",
+        conflicts_with = "no_dir_listing"
+    )]
+    list_json: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "no_dir_listing",
+        help = "HTML file to use for directory listings instead of the built-in one. {directory} and {lis} are replaced with the directory and its entries"
+    )]
+    listing_template: Option<PathBuf>,
+    #[arg(
+        long,
+        num_args = 0..=1,
+        default_missing_value = "/metrics",
+        help = "Serve Prometheus metrics at this path, /metrics if none is given"
+    )]
+    metrics_path: Option<String>,
+    #[arg(
+        long,
+        value_parser = clap::builder::NonEmptyStringValueParser::new(),
+        help = "Enable GET /admin/shutdown, which shuts the server down gracefully for requests with this token in an X-Admin-Token header"
+    )]
+    admin_token: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ErrorFormat {
+    /// Just the status code
+    Text,
+    /// A small JSON object with the error and the seconds to wait
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AccessLogFormat {
+    /// No access log
+    None,
+    /// NCSA Common Log Format
+    Clf,
+}
+
+fn parse_credentials(credentials: &str) -> Result<String, String> {
+    match credentials.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(credentials.to_string()),
+        _ => Err("expected USER:PASSWORD".to_string()),
+    }
+}
+
+// Plain bytes, or with a K, M or G suffix for powers of 1024
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    let (number, multiplier) = match rate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&rate[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&rate[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&rate[..i], 1 << 30),
+        _ => (rate, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .filter(|&rate| rate > 0)
+        .ok_or_else(|| "expected a number of bytes per second, like 500K or 1M".to_string())
+}
+
+// Handlers spend most of their time waiting on the network, so we want more workers than cores
+fn default_workers() -> NonZero<usize> {
+    thread::available_parallelism()
+        .unwrap_or(NonZero::<usize>::MIN)
+        .saturating_mul(NonZero::new(4).expect("4 is not zero"))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DirListing {
+    Disabled,
+    Names,
+    // Names, sizes and modification times
+    Details,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DotFiles {
+    Hide,
+    Serve,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ListingFormat {
+    // HTML, or JSON for clients that ask for it
+    Negotiated,
+    Json,
+}
+
+/// Server settings shared between all connection handlers.
+///
+/// Built from the same options as the binary with [`Config::from_cli`], and served with [`serve`].
+#[derive(Clone)]
+pub struct Config {
+    /// Canonicalized web root
+    root: PathBuf,
+    /// The one file to serve with --file
+    file: Option<PathBuf>,
+    blacklist: Vec<PathBuf>,
+    allow_symlinks: bool,
+    follow_symlinks: bool,
+    /// Whether files and directories starting with a dot are served, from --serve-hidden-files
+    dot_files: DotFiles,
+    tls: Option<Arc<ServerConfig>>,
+    gzip_types: Vec<String>,
+    gzip_min_size: u64,
+    /// Bytes per second for file bodies, from --max-rate
+    max_rate: Option<u64>,
+    /// Added to the Content-Type of text files
+    charset: Option<String>,
+    /// Seconds files can be cached for, from --cache-max-age
+    cache_max_age: u64,
+    /// File names that change whenever their contents do, from --immutable-pattern
+    immutable_pattern: Option<Regex>,
+    dir_listing: DirListing,
+    /// From --listing-template, read once at startup
+    listing_template: Option<String>,
+    listing_format: ListingFormat,
+    index: Vec<String>,
+    /// `user:password` that requests have to authenticate with
+    auth: Option<String>,
+    keep_alive_timeout: Duration,
+    /// Requests served on one connection before closing it, or 0 for no limit
+    max_keep_alive_requests: u32,
+    /// Common Log Format access log, if enabled
+    access_log: Option<Arc<Mutex<File>>>,
+    header_timeout: Duration,
+    max_header_bytes: usize,
+    max_uri_length: usize,
+    health_path: Option<String>,
+    cors: Option<Cors>,
+    metrics: Arc<Metrics>,
+    metrics_path: Option<String>,
+    /// From --admin-token. Without it there is no shutdown endpoint.
+    admin_token: Option<String>,
+    /// Set to shut down gracefully, by a signal or the shutdown endpoint
+    shutdown: Arc<AtomicBool>,
+    /// Shared with the workers, which rate-limit the clients behind trusted proxies
+    ratelimiter: Option<Arc<Mutex<RateLimiter>>>,
+    trusted_proxies: Vec<IpNet>,
+    /// The settings for each --vhost, which only differ in their root
+    vhosts: Vec<(String, Self)>,
+    strict_vhosts: bool,
+    /// Request paths to their redirect status and target, from --redirect and --temporary-redirect
+    redirects: HashMap<String, (u16, String)>,
+    /// Where to listen and which connections to let in
+    listen: Listen,
+    /// How responses and log lines look
+    output: Output,
+}
+
+// Where the server listens and which connections it lets in. Virtual hosts share the default site's.
+#[derive(Clone)]
+struct Listen {
+    // `address:port` for each TCP listener, unless there's a Unix socket instead
+    addrs: Vec<String>,
+    unix_socket: Option<PathBuf>,
+    backlog: i32,
+    tcp_nodelay: bool,
+    access: AccessList,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    // None to serve every connection on the accepting thread, from --single-threaded
+    workers: Option<NonZero<usize>>,
+}
+
+// How responses and log lines look. Responses are built all over the place, so these end up in globals once serving starts.
+#[derive(Clone, Copy)]
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "Each one is its own command line switch"
+)]
+struct Output {
+    server_header: bool,
+    quiet_errors: bool,
+    json_errors: bool,
+    verbose_access: bool,
+    log_ip_privacy: bool,
+}
+
+impl Output {
+    const fn from_cli(cli: &Cli) -> Self {
+        Self {
+            server_header: !cli.no_server_header,
+            quiet_errors: cli.quiet_errors,
+            json_errors: matches!(cli.error_format, ErrorFormat::Json),
+            verbose_access: cli.verbose_access,
+            log_ip_privacy: cli.log_ip_privacy,
+        }
+    }
+
+    fn apply(self) {
+        SEND_SERVER_HEADER.store(self.server_header, Ordering::Relaxed);
+        QUIET_ERRORS.store(self.quiet_errors, Ordering::Relaxed);
+        JSON_ERRORS.store(self.json_errors, Ordering::Relaxed);
+        VERBOSE_ACCESS.store(self.verbose_access, Ordering::Relaxed);
+        LOG_IP_PRIVACY.store(self.log_ip_privacy, Ordering::Relaxed);
+    }
+}
+
+impl Config {
+    /// Resolves the web root, blacklist, TLS and everything else `cli` points at.
+    ///
+    /// # Errors
+    ///
+    /// If any of it can't be used, like a missing web root, an unreadable certificate or a listing template without a `{lis}` placeholder.
+    pub fn from_cli(cli: &Cli) -> io::Result<Self> {
+        setup_config(cli)
+    }
+
+    // The site a request is for, going by its Host header. None for unknown hosts with --strict-vhosts.
+    fn site(&self, request: &Request) -> Option<&Self> {
+        if self.vhosts.is_empty() {
+            return Some(self);
+        }
+        let host = request.header("Host").map(host_name).unwrap_or_default();
+        self.vhosts
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(host))
+            .map(|(_, site)| site)
+            .or_else(|| (!self.strict_vhosts).then_some(self))
+    }
+
+    fn trusts(&self, peer: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(peer))
+    }
+
+    // Behind trusted proxies, the client is the last address in X-Forwarded-For that
+    // wasn't added by one of them. Anything further left could have been made up.
+    fn client_ip(&self, peer: IpAddr, request: &Request) -> IpAddr {
+        let mut client = peer;
+        if let Some(forwarded) = request.header("X-Forwarded-For") {
+            for hop in forwarded.rsplit(',') {
+                if !self.trusts(client) {
+                    break;
+                }
+                let Ok(ip) = hop.trim().parse() else {
+                    break;
+                };
+                client = ip;
+            }
+        }
+        client
+    }
+
+    // Counts the client's request, and returns the seconds left on the ratelimit if it should be turned away
+    fn ratelimited(&self, client: IpAddr) -> Option<u64> {
+        self.check_ratelimit(client, true)
+    }
+
+    // Turns away clients that are already rate-limited without counting anything, since the
+    // requests on their connection get counted one by one once they're read
+    fn ratelimited_connection(&self, client: IpAddr) -> Option<u64> {
+        self.check_ratelimit(client, false)
+    }
+
+    fn check_ratelimit(&self, client: IpAddr, count: bool) -> Option<u64> {
+        let left = self.ratelimiter.as_ref()?.lock().ok()?.check(client, count);
+        if left.is_some() {
+            self.metrics.ratelimited.fetch_add(1, Ordering::Relaxed);
+        }
+        left
+    }
+}
+
+const fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown Error",
+    }
+}
+
+// Set once at startup from --no-server-header. Responses are built all over the place, so this is global.
+static SEND_SERVER_HEADER: AtomicBool = AtomicBool::new(true);
+
+thread_local! {
+    // Headers that depend on the request, like CORS, added to every response while it is being handled.
+    // A request is handled start to finish on one thread, so this saves passing them to everything that responds.
+    static REQUEST_HEADERS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+    // The HTTP version responses go out with. Same reasoning as REQUEST_HEADERS.
+    static RESPONSE_VERSION: Cell<&'static str> = const { Cell::new("1.1") };
+    // Whether the connection closes after this response. Anything sent before the request is understood does.
+    static CLOSES_CONNECTION: Cell<bool> = const { Cell::new(true) };
+}
+
+// Set once at startup from --quiet-errors, for the same reason as SEND_SERVER_HEADER
+static QUIET_ERRORS: AtomicBool = AtomicBool::new(false);
+// Set once at startup from --error-format json
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+// The plain body of an error response, which is just the status code unless --quiet-errors is on
+fn error_body(status: u16) -> String {
+    if QUIET_ERRORS.load(Ordering::Relaxed) {
+        String::new()
+    } else {
+        format!("{status}\n")
+    }
+}
+
+/// Builds the status line and header block of a response, including the blank line that ends it
+fn response_head(status: u16, headers: &[(&str, String)]) -> String {
+    let mut head = format!(
+        "HTTP/{} {status} {}\r\n",
+        RESPONSE_VERSION.get(),
+        status_text(status)
+    );
+    // Required on every response from an HTTP/1.1 server with a clock (RFC 7231 section 7.1.1.2)
+    head.push_str("Date: ");
+    head.push_str(&http_date(OffsetDateTime::now_utc()));
+    head.push_str("\r\n");
+    if SEND_SERVER_HEADER.load(Ordering::Relaxed) {
+        head.push_str(concat!(
+            "Server: SimpleWebServer/",
+            env!("CARGO_PKG_VERSION"),
+            "\r\n"
+        ));
+    }
+    for (name, value) in headers {
+        head.push_str(name);
+        head.push_str(": ");
+        head.push_str(value);
+        head.push_str("\r\n");
+    }
+    // Errors are usually temporary, so a cache holding on to one would outlive it
+    if status >= 400 && !headers.iter().any(|(name, _)| *name == "Cache-Control") {
+        head.push_str("Cache-Control: no-store\r\n");
+    }
+    REQUEST_HEADERS.with_borrow(|headers| {
+        for (name, value) in headers {
+            head.push_str(name);
+            head.push_str(": ");
+            head.push_str(value);
+            head.push_str("\r\n");
+        }
+    });
+    // A 1.0 client expects the connection to close anyway, a 1.1 one has to be told
+    if CLOSES_CONNECTION.get() && RESPONSE_VERSION.get() != "1.0" {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    head
+}
+
+fn error_stream(stream: &mut impl Write, error_id: u16, head_only: bool) {
+    let body = error_body(error_id);
+    let head = response_head(error_id, &[("Content-Length", body.len().to_string())]);
+    let body = if head_only { "" } else { &body };
+    if stream
+        .write_all(format!("{head}{body}").as_bytes())
+        .is_err()
+    {
+        error!("Could not write error code to stream.");
+    }
+    if stream.flush().is_err() {
+        error!("Failed flushing stream.");
+    }
+}
+
+// Serves `<error_id>.html` from the web root if there is one, otherwise a plain error
+fn error_page(stream: &mut impl Write, config: &Config, error_id: u16, head_only: bool) {
+    let page = config.root.join(format!("{error_id}.html"));
+    // The page could be a symlink, so it gets the same checks as any other file
+    let body = page
+        .canonicalize()
+        .ok()
+        .filter(|canon| servable(canon, &page, config))
+        .and_then(|canon| fs::read(canon).ok());
+
+    let Some(body) = body else {
+        error_stream(stream, error_id, head_only);
+        return;
+    };
+
+    let head = response_head(
+        error_id,
+        &[
+            (
+                "Content-Type",
+                with_charset("text/html", config.charset.as_deref()),
+            ),
+            ("Content-Length", body.len().to_string()),
+        ],
+    );
+    let body = if head_only { &[][..] } else { &body };
+    if stream
+        .write_all(head.as_bytes())
+        .and_then(|()| stream.write_all(body))
+        .is_err()
+    {
+        error!("Could not write error page to stream.");
+    }
+    if stream.flush().is_err() {
+        error!("Failed flushing stream.");
+    }
+}
+
+// Types that are text even though they don't say so
+const TEXT_TYPES: [&str; 3] = [
+    "application/json",
+    "application/javascript",
+    "application/xml",
+];
+
+// Only text has a charset. Binary types would just get confused by one.
+fn with_charset(mime: &str, charset: Option<&str>) -> String {
+    let text = mime.starts_with("text/")
+        || TEXT_TYPES.contains(&mime)
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json");
+    match charset {
+        Some(charset) if text => format!("{mime}; charset={charset}"),
+        _ => mime.to_string(),
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let Some(extension) = path.extension() else {
+        return "application/octet-stream";
+    };
+
+    match extension.to_string_lossy().to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "xml" => "application/xml",
+        "csv" => "text/csv",
+        "md" => "text/markdown",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "bmp" => "image/bmp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+// Set once at startup from --verbose-access, for the same reason as SEND_SERVER_HEADER
+static VERBOSE_ACCESS: AtomicBool = AtomicBool::new(false);
+// Set once at startup from --log-ip-privacy, for the same reason as SEND_SERVER_HEADER
+static LOG_IP_PRIVACY: AtomicBool = AtomicBool::new(false);
+
+// How a client shows up in the logs. Rate limiting and access lists always see the full address.
+fn log_ip(ip: IpAddr) -> IpAddr {
+    if !LOG_IP_PRIVACY.load(Ordering::Relaxed) {
+        return ip;
+    }
+    let prefix = if ip.to_canonical().is_ipv4() { 24 } else { 48 };
+    IpNet::around(ip, prefix).network
+}
+
+fn print_message(ip: IpAddr, request: &Request, error_id: u16) {
+    let (ip, method, path) = (log_ip(ip), request.method, &request.path);
+    if VERBOSE_ACCESS.load(Ordering::Relaxed) {
+        let header = |name| log_safe(request.header(name).unwrap_or("-"));
+        info!(
+            "{ip}: {method} {path} - {error_id} \"{}\" \"{}\"",
+            header("Referer"),
+            header("User-Agent")
+        );
+    } else if error_id == 200 {
+        trace!("{ip}: {method} {path} - {error_id}");
+    } else {
+        info!("{ip}: {method} {path} - {error_id}");
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Get,
+    Head,
+    Options,
+}
+
+// Sent in the Allow header, so keep it in sync with `Method`
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Options => "OPTIONS",
+        })
+    }
+}
+
+struct Request {
+    method: Method,
+    path: String,
+    // The first line of the request, as received
+    line: String,
+    // Like "1.1"
+    version: String,
+    // Keyed by lowercase name
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    // Header names are case-insensitive
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    fn connection_option(&self, option: &str) -> bool {
+        self.header("Connection").is_some_and(|connection| {
+            connection
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(option))
+        })
+    }
+
+    // HTTP/1.1 connections are persistent unless the client says otherwise,
+    // HTTP/1.0 ones only if the client asks (RFC 7230 section 6.3)
+    fn keep_alive(&self) -> bool {
+        // We never read request bodies, so whatever follows one can't be parsed as the next request
+        let has_body = self.header("Transfer-Encoding").is_some()
+            || self
+                .header("Content-Length")
+                .is_some_and(|length| length.trim() != "0");
+        let persistent = if self.http_1_0() {
+            self.connection_option("keep-alive")
+        } else {
+            !self.connection_option("close")
+        };
+        persistent && !has_body
+    }
+
+    fn http_1_0(&self) -> bool {
+        self.version.as_str() < "1.1"
+    }
+
+    // HEAD gets the same headers as GET, Content-Length included, but never a body
+    fn head_only(&self) -> bool {
+        self.method == Method::Head
+    }
+}
+
+// Also how much of a rejected connection's request gets looked at or thrown away
+const DEFAULT_MAX_HEADER_BYTES: usize = 8192;
+// Bounds the path we keep around and log for every request
+const DEFAULT_MAX_URI_LENGTH: usize = 8192;
+
+// Parses everything after the request line. `lines` takes care of both CRLF and bare LF endings.
+fn parse_headers(header: &str) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    for (name, value) in header
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+    {
+        let value = value.trim();
+        headers
+            .entry(name.trim().to_ascii_lowercase())
+            .and_modify(|existing: &mut String| {
+                // Repeated headers are equivalent to one comma-separated list (RFC 7230 section 3.2.2)
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    headers
+}
+
+// Some clients send a stray line break after a request body, which we should ignore (RFC 7230 section 3.5)
+fn skip_blank_lines(buffer: &mut Vec<u8>) {
+    let blank = buffer
+        .iter()
+        .take_while(|&&byte| byte == b'\r' || byte == b'\n')
+        .count();
+    buffer.drain(..blank);
+}
+
+// Returns the length of the header block (terminator included) if it has been fully received
+fn header_end(buffer: &[u8]) -> Option<usize> {
+    // Bare LFs aren't valid but are common enough that we accept them too
+    let crlf = buffer
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4);
+    let lf = buffer
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map(|pos| pos + 2);
+    crlf.into_iter().chain(lf).min()
+}
+
+// The request target as far as it has arrived, which is everything between the first space and the next
+fn request_target(buffer: &[u8]) -> &[u8] {
+    let line = buffer.split(|&b| b == b'\n').next().unwrap_or_default();
+    let target = line.splitn(2, |&b| b == b' ').nth(1).unwrap_or_default();
+    target
+        .split(|&b| b == b' ' || b == b'\r')
+        .next()
+        .unwrap_or_default()
+}
+
+// Reads until the end of the header block and returns its size, answering with an error if that goes wrong.
+// `buffer` holds whatever was read past the end of the previous request on this connection.
+// The clock for `header_timeout` starts with the first byte, since waiting for a request is the idle timeout's job.
+fn read_header(
+    stream: &mut (impl Read + Write),
+    peer: &IpAddr,
+    buffer: &mut Vec<u8>,
+    header_timeout: Duration,
+    max_header_bytes: usize,
+    max_uri_length: usize,
+) -> Option<usize> {
+    let peer = log_ip(*peer);
+    let mut chunk: [u8; 1024] = [0; 1024];
+    let mut started = (!buffer.is_empty()).then(Instant::now);
+    // Headers can arrive over multiple reads, so keep reading until we see the end of them
+    loop {
+        skip_blank_lines(buffer);
+        // A long header block can arrive with the end in the same read, so check where it ends too
+        if let Some(end) = header_end(buffer)
+            && end <= max_header_bytes
+        {
+            return Some(end);
+        }
+        if buffer.len() > max_header_bytes {
+            // A long target is what usually overflows the header block, so blame it where that's the case
+            let target = request_target(buffer).len();
+            if target > max_uri_length {
+                warn!("Request target from {peer} is too long ({target}+ bytes).");
+                error_stream(stream, 414, false);
+                return None;
+            }
+            warn!("Request headers from {peer} are too large.");
+            error_stream(stream, 431, false);
+            return None;
+        }
+        // Checked on every read so dribbling bytes slowly doesn't keep the connection open (Slowloris)
+        if started.is_some_and(|started| started.elapsed() > header_timeout) {
+            warn!("Request headers from {peer} took too long.");
+            error_stream(stream, 408, false);
+            return None;
+        }
+        match stream.read(&mut chunk) {
+            // The client closed the connection or went idle between requests
+            Ok(0) | Err(_) if buffer.is_empty() => return None,
+            Ok(0) => return Some(buffer.len()),
+            Ok(read) => {
+                started.get_or_insert_with(Instant::now);
+                buffer.extend_from_slice(&chunk[..read]);
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                warn!("Request headers from {peer} took too long.");
+                error_stream(stream, 408, false);
+                return None;
+            }
+            Err(_) => {
+                error!("Could not read get request.");
+                return Some(buffer.len());
+            }
+        }
+    }
+}
+
+fn get_path(
+    stream: &mut (impl Read + Write),
+    peer: &IpAddr,
+    buffer: &mut Vec<u8>,
+    header_timeout: Duration,
+    max_header_bytes: usize,
+    max_uri_length: usize,
+) -> Option<Request> {
+    static HEADER_REGEX: std::sync::LazyLock<Regex> = std::sync::LazyLock::new(|| {
+        Regex::new(r"^([A-Z]+) (\*|/.*?)(?:\?.*)? HTTP/(\d\.\d)(?s).*$")
+            .expect("Unable to create regex")
+    });
+
+    //println!("Connection from {}", peer.to_string());
+
+    let header_size = read_header(
+        stream,
+        peer,
+        buffer,
+        header_timeout,
+        max_header_bytes,
+        max_uri_length,
+    )?;
+    let peer = log_ip(*peer);
+    let header = String::from_utf8_lossy(&buffer[..header_size]).into_owned();
+    buffer.drain(..header_size);
+
+    let target = header.split(' ').nth(1).unwrap_or_default();
+    if let Some(form) = unsupported_target_form(target) {
+        warn!(
+            "Rejecting {form} request target from {peer}, since this isn't a proxy: {}",
+            log_safe(target)
+        );
+        error_stream(stream, 400, false);
+        return None;
+    }
+    if !HEADER_REGEX.is_match(&header) {
+        warn!("Malformed request from {peer}: {}", log_safe(&header));
+        error_stream(stream, 400, false);
+        return None;
+    }
+
+    let m = HEADER_REGEX
+        .captures(&header)
+        .expect("Could not get captures from regex");
+    // We speak 1.1, but older clients might not understand what it adds, so they get answered in 1.0
+    RESPONSE_VERSION.set(if &m[3] < "1.1" { "1.0" } else { "1.1" });
+    let head_only = &m[1] == "HEAD";
+    if target.len() > max_uri_length {
+        warn!(
+            "Request target from {peer} is too long ({} bytes).",
+            target.len()
+        );
+        error_stream(stream, 414, head_only);
+        return None;
+    }
+
+    let method = match &m[1] {
+        "GET" => Method::Get,
+        "HEAD" => Method::Head,
+        "OPTIONS" => Method::Options,
+        // A well-formed request we just don't do, which isn't the client's mistake like a 400 would say
+        unsupported => {
+            info!("{peer}: {unsupported} {} - 405", log_safe(&m[2]));
+            method_not_allowed_stream(stream);
+            return None;
+        }
+    };
+    // `*` means the server as a whole, which only makes sense for OPTIONS
+    if &m[2] == "*" && method != Method::Options {
+        warn!("Malformed request from {peer}: {}", log_safe(&header));
+        error_stream(stream, 400, head_only);
+        return None;
+    }
+
+    // Decoding has to happen before the path is resolved so the directory escape check sees the real path.
+    // That includes %2F, so /a%2Fb is just another way to write /a/b (no file name can contain a slash anyway),
+    // and everything that matches on paths, like --redirect and --health-path, sees it the same way.
+    // The query string was already cut off above, so whatever it has encoded can't end up in the path.
+    let Some(path) = percent_decode(&m[2]) else {
+        warn!("Malformed path from {peer}: {}", log_safe(&m[2]));
+        error_stream(stream, 400, head_only);
+        return None;
+    };
+    // No file name needs these, and a null byte or newline in a path only confuses the filesystem or our logs
+    if path.chars().any(char::is_control) {
+        warn!(
+            "Path with control characters from {peer}: {}",
+            log_safe(&path)
+        );
+        error_stream(stream, 400, head_only);
+        return None;
+    }
+
+    let request = Request {
+        method,
+        path,
+        line: header.lines().next().unwrap_or_default().to_string(),
+        version: m[3].to_string(),
+        headers: parse_headers(&header),
+    };
+    // Required since HTTP/1.1 (RFC 7230 section 5.4). Older clients don't have to send it.
+    if request.version.as_str() >= "1.1" && request.header("Host").is_none() {
+        warn!(
+            "Request without a Host header from {peer}: {}",
+            log_safe(&request.line)
+        );
+        error_stream(stream, 400, head_only);
+        return None;
+    }
+    Some(request)
+}
+
+// Names the form of a request target (RFC 7230 section 5.3) we deliberately don't serve.
+// Absolute-form (http://host/path) is for proxies and authority-form (host:port) for CONNECT.
+// Serving the path out of either would route on a host that was never checked against the Host header.
+// Origin-form (/path) and asterisk-form (*) are fine, and anything else is just malformed.
+fn unsupported_target_form(target: &str) -> Option<&'static str> {
+    if target.starts_with('/') || target == "*" {
+        return None;
+    }
+    if let Some((scheme, rest)) = target.split_once(':')
+        && rest.starts_with("//")
+        && !scheme.is_empty()
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    {
+        return Some("absolute-form");
+    }
+    if let Some((host, port)) = target.rsplit_once(':')
+        && !host.is_empty()
+        && !port.is_empty()
+        && port.chars().all(|c| c.is_ascii_digit())
+    {
+        return Some("authority-form");
+    }
+    None
+}
+
+// Escapes control characters in request data so it can't forge log lines or mess with the terminal
+fn log_safe(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_control() {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+// Returns None if there is a malformed escape sequence or the result isn't valid UTF-8
+fn percent_decode(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = char::from(bytes.next()?).to_digit(16)?;
+            let low = char::from(bytes.next()?).to_digit(16)?;
+            decoded.push(u8::try_from(high * 16 + low).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+/// Maps a decoded request path to the file it names under `root`, trying `.html` for paths without an extension.
+///
+/// Returns the canonical path and the path as requested.
+/// Neither is checked to be inside `root` yet, since symlinks get a say in that.
+///
+/// # Errors
+///
+/// Fails with [`io::ErrorKind::NotFound`] or [`io::ErrorKind::NotADirectory`] if there is no such file,
+/// with [`io::ErrorKind::InvalidFilename`] or [`io::ErrorKind::InvalidInput`] if there can't be one by that name,
+/// or with whatever else went wrong looking for it, like a permission problem.
+pub fn server_path_to_local_path(
+    requested_path: &str,
+    root: &Path,
+) -> io::Result<(PathBuf, PathBuf)> {
+    // Path parsing
+    let mut path = absolute(PathBuf::from(&requested_path))?;
+
+    let path_root = if cfg!(windows) { "C:\\" } else { "/" };
+
+    // Convert into a path inside the web root
+    path = root.join(if let Ok(stripped) = path.strip_prefix(path_root) {
+        stripped
+    } else {
+        error!(
+            "Could not strip root (convert into relative path): {}",
+            path.display()
+        );
+        return Err(io::ErrorKind::NotFound.into());
+    });
+    // Trying adding .html after original request 404s
+    if !path.exists() && path.extension().is_none() {
+        trace!(
+            "{} not found. Using {}.html instead",
+            path.display(),
+            path.display()
+        );
+        // Add .html to non html paths
+        path.set_extension("html");
+    }
+
+    let abpath = absolute(&path)?;
+    Ok((path.canonicalize()?, abpath))
+}
+
+// Whether any part of `abpath` below the root is a symlink.
+// Anything that isn't below the root counts too, since we can't tell.
+fn through_symlink(abpath: &Path, root: &Path) -> bool {
+    let Ok(relative) = abpath.strip_prefix(root) else {
+        return true;
+    };
+    let mut current = root.to_path_buf();
+    relative.components().any(|component| {
+        current.push(component);
+        current.is_symlink()
+    })
+}
+
+#[cfg(not(on_nightly))]
+fn check_path(path: &Path, _: &Path, _: bool, root: &Path) -> bool {
+    path.starts_with(root)
+}
+
+#[cfg(on_nightly)]
+fn check_path(path: &Path, abpath: &Path, allow_symlinks: bool, root: &Path) -> bool {
+    if allow_symlinks && abpath.is_symlink() {
+        // This is why we need nightly: for normalize_lexically
+        let Ok(ab_sym) = abpath.normalize_lexically() else {
+            error!("Could not normalize path!");
+            return false;
+        };
+        // Now just make sure the symlink itself is within our dir
+        if ab_sym.starts_with(root) {
+            info!(
+                "Redirecting symlink {} to {}.",
+                ab_sym.display(),
+                path.display()
+            );
+            true
+        } else {
+            false
+        }
+    } else {
+        path.starts_with(root)
+    }
+}
+
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+// Holds writes back to an average of `rate` bytes per second by sleeping after each one, for --max-rate
+struct Throttle<W> {
+    inner: W,
+    rate: Option<u64>,
+    started: Instant,
+    written: u64,
+}
+
+impl<W: Write> Throttle<W> {
+    fn new(inner: W, rate: Option<u64>) -> Self {
+        Self {
+            inner,
+            rate,
+            started: Instant::now(),
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for Throttle<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(rate) = self.rate else {
+            return self.inner.write(buf);
+        };
+        // A tenth of a second's worth at a time, so a whole file chunk doesn't go out in one burst
+        let most = usize::try_from(rate / 10).unwrap_or(usize::MAX).max(1);
+        let written = self.inner.write(&buf[..buf.len().min(most)])?;
+        self.written += u64::try_from(written).unwrap_or(u64::MAX);
+        let due = u128::from(self.written) * 1_000_000_000 / u128::from(rate);
+        let due = Duration::from_nanos(u64::try_from(due).unwrap_or(u64::MAX));
+        if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(wait);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// A year, the traditional "never expires" (RFC 2616 section 14.21)
+const IMMUTABLE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+// IMF-fixdate, the date format HTTP/1.1 servers have to send (RFC 7231 section 7.1.1.1)
+const HTTP_DATE_FORMAT: &[BorrowedFormatItem<'_>] = format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
+
+fn http_date(time: OffsetDateTime) -> String {
+    time.to_offset(UtcOffset::UTC)
+        .format(HTTP_DATE_FORMAT)
+        .unwrap_or_default()
+}
+
+// Returns None for anything that isn't an IMF-fixdate
+fn parse_http_date(date: &str) -> Option<OffsetDateTime> {
+    PrimitiveDateTime::parse(date, HTTP_DATE_FORMAT)
+        .ok()
+        .map(PrimitiveDateTime::assume_utc)
+}
+
+// Built from the size and modification time so it's cheap and survives restarts
+fn file_etag(len: u64, mtime: Option<SystemTime>, encoding: Option<&str>) -> String {
+    let mtime = mtime
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    // A compressed body is a different representation, so it needs its own tag
+    let encoding = encoding.map_or_else(String::new, |encoding| format!("-{encoding}"));
+    format!("\"{len:x}-{:x}{encoding}\"", mtime.as_nanos())
+}
+
+// If-None-Match takes precedence over If-Modified-Since when both are sent (RFC 7232 section 6)
+fn not_modified(request: &Request, etag: &str, modified: Option<OffsetDateTime>) -> bool {
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        // If-None-Match uses weak comparison, so W/ prefixes are ignored
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+    }
+    modified.is_some_and(|modified| {
+        request
+            .header("If-Modified-Since")
+            .and_then(parse_http_date)
+            .is_some_and(|since| modified <= since)
+    })
+}
+
+// The Range header, unless If-Range says the file changed since the client got the first part of it.
+// Then the whole file is sent so the client doesn't stitch together two versions (RFC 7233 section 3.2).
+// ETags have to match strongly, so weak ones never do, and dates have to match exactly.
+fn if_range<'a>(
+    request: &'a Request,
+    etag: &str,
+    modified: Option<OffsetDateTime>,
+) -> Option<&'a str> {
+    let range = request.header("Range")?;
+    let Some(validator) = request.header("If-Range").map(str::trim) else {
+        return Some(range);
+    };
+    let unchanged = if validator.starts_with('"') || validator.starts_with("W/") {
+        validator == etag
+    } else {
+        modified.is_some_and(|modified| parse_http_date(validator) == Some(modified))
+    };
+    unchanged.then_some(range)
+}
+
+// Bigger files are streamed uncompressed instead of being compressed in memory
+const MAX_GZIP_SIZE: u64 = 16 * 1024 * 1024;
+
+// A q-value of 0 means the client explicitly doesn't want that encoding
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    let codings = accept_encoding.split(',').map(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let refused = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        (name, refused)
+    });
+    let named = |name: &str| {
+        name.eq_ignore_ascii_case(encoding)
+            || (encoding == "gzip" && name.eq_ignore_ascii_case("x-gzip"))
+    };
+    // Naming the encoding outranks `*`, so "gzip;q=0, *" still refuses gzip
+    let mut explicit = codings.clone().filter(|(name, _)| named(name)).peekable();
+    if explicit.peek().is_some() {
+        return explicit.any(|(_, refused)| !refused);
+    }
+    codings
+        .filter(|(name, _)| *name == "*")
+        .any(|(_, refused)| !refused)
+}
+
+// Prebuilt compressed versions of a file sit next to it with these extensions, best first
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+// Swaps `file` for a prebuilt compressed version the client accepts, like `style.css.gz` for `style.css`,
+// and returns its encoding. Ranges always come from the file itself.
+fn with_precompressed(
+    file: File,
+    path: &Path,
+    request: &Request,
+    config: &Config,
+) -> (File, Option<&'static str>) {
+    if request.header("Range").is_some() {
+        return (file, None);
+    }
+    let Some(accept_encoding) = request.header("Accept-Encoding") else {
+        return (file, None);
+    };
+    let sibling = PRECOMPRESSED
+        .iter()
+        .filter(|(encoding, _)| accepts_encoding(accept_encoding, encoding))
+        .find_map(|(encoding, extension)| {
+            let mut sibling = path.as_os_str().to_owned();
+            sibling.push(format!(".{extension}"));
+            let sibling = PathBuf::from(sibling);
+            let canonical = sibling.canonicalize().ok()?;
+            // The same checks as for the file itself
+            if !canonical.starts_with(&config.root)
+                || config.blacklist.contains(&canonical)
+                || (!config.follow_symlinks && sibling.is_symlink())
+                || !canonical.is_file()
+            {
+                return None;
+            }
+            File::open(canonical).ok().map(|file| (file, *encoding))
+        });
+    match sibling {
+        Some((sibling, encoding)) => (sibling, Some(encoding)),
+        None => (file, None),
+    }
+}
+
+// Which part of a file a Range header asks for
+enum ByteRange {
+    Full,
+    // First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Only single ranges are supported. Anything we don't understand gets the full file, as the spec allows.
+fn byte_range(range: Option<&str>, len: u64) -> ByteRange {
+    let Some((start, end)) = range
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.trim().split_once('-'))
+    else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // Suffix range: the last `end` bytes
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let end = if end.is_empty() {
+        u64::MAX
+    } else if let Ok(end) = end.parse::<u64>() {
+        end
+    } else {
+        return ByteRange::Full;
+    };
+    if end < start {
+        ByteRange::Full
+    } else if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end.min(len - 1))
+    }
+}
+
+// Whether we may serve `path` at all. Anything we may not looks like it doesn't exist.
+fn servable(path: &Path, abpath: &Path, config: &Config) -> bool {
+    if !config.follow_symlinks && through_symlink(abpath, &config.root) {
+        warn!("Not following symlink to {}", path.display());
+        return false;
+    }
+
+    // Protection from directory escape
+    if !check_path(path, abpath, config.allow_symlinks, &config.root) {
+        error!("!!! Directory escape prevented: {} !!!", path.display());
+        return false;
+    }
+
+    // Blacklisting
+    if config
+        .blacklist
+        .iter()
+        .any(|blacklisted| blacklisted == path)
+    {
+        warn!("Blacklisted file requested: {}", path.display());
+        return false;
+    }
+
+    // Both, so neither a symlink to a dotfile nor one inside a hidden directory gets through
+    if config.dot_files == DotFiles::Hide
+        && (hidden(abpath, &config.root) || hidden(path, &config.root))
+    {
+        debug!("Hidden file requested: {}", abpath.display());
+        return false;
+    }
+    true
+}
+
+// Whether any part of `path` below the root starts with a dot, like .git/config or .env.
+// .well-known is left alone since things like ACME challenges have to be reachable (RFC 8615).
+fn hidden(path: &Path, root: &Path) -> bool {
+    path.strip_prefix(root).is_ok_and(|relative| {
+        relative
+            .components()
+            .any(|component| matches!(component, Component::Normal(name) if hidden_name(name)))
+    })
+}
+
+fn hidden_name(name: &OsStr) -> bool {
+    name.as_encoded_bytes().starts_with(b".") && name != ".well-known"
+}
+
+// Errors are answered with an error page. Err means the response got cut off, so the connection can't be reused.
+fn serve_local_file(
+    path: &Path,
+    stream: &mut impl Write,
+    peer: &IpAddr,
+    config: &Config,
+    request: &Request,
+    abpath: &Path,
+) -> Result<(), ()> {
+    if !servable(path, abpath, config) {
+        error_page(stream, config, 404, request.head_only());
+        return Ok(());
+    }
+
+    if path.is_dir() {
+        return serve_directory(path, stream, peer, config, request);
+    }
+    serve_file(path, stream, peer, config, request)
+}
+
+// How long browsers can keep a file for, if --cache-max-age or --immutable-pattern say so
+fn cache_control(path: &Path, config: &Config) -> Option<String> {
+    // A hashed name gets a new one when the file changes, so the old one never needs checking again
+    let immutable = config.immutable_pattern.as_ref().is_some_and(|pattern| {
+        path.file_name()
+            .is_some_and(|name| pattern.is_match(&name.to_string_lossy()))
+    });
+    if immutable {
+        Some(format!("public, max-age={IMMUTABLE_MAX_AGE}, immutable"))
+    } else if config.cache_max_age > 0 {
+        Some(format!("public, max-age={}", config.cache_max_age))
+    } else {
+        None
+    }
+}
+
+fn serve_file(
+    path: &Path,
+    stream: &mut impl Write,
+    peer: &IpAddr,
+    config: &Config,
+    request: &Request,
+) -> Result<(), ()> {
+    let stream = &mut Throttle::new(stream, config.max_rate);
+    let Ok(file) = File::open(path) else {
+        // This state will most likely occur if someone is maliciously manipulating files on the host.
+        error_page(stream, config, 404, request.head_only());
+        error!("!!! TOCTOU Prevented: {} !!!", path.display());
+        return Ok(());
+    };
+    let (mut file, precompressed) = with_precompressed(file, path, request, config);
+
+    let Ok(metadata) = file.metadata() else {
+        error_page(stream, config, 500, request.head_only());
+        error!("Could not read metadata of file: {}", path.display());
+        return Ok(());
+    };
+    let len = metadata.len();
+    let mtime = metadata.modified().ok();
+    // HTTP dates only have second precision, so the rest has to go for comparisons to work
+    let modified = mtime
+        .map(OffsetDateTime::from)
+        .and_then(|modified| modified.replace_nanosecond(0).ok());
+    let compressible = len >= config.gzip_min_size
+        && len <= MAX_GZIP_SIZE
+        && config
+            .gzip_types
+            .iter()
+            .any(|mime| mime == content_type(path));
+    // Ranges are always served from the uncompressed file
+    let gzip = compressible
+        && precompressed.is_none()
+        && request.header("Range").is_none()
+        && request
+            .header("Accept-Encoding")
+            .is_some_and(|accept_encoding| accepts_encoding(accept_encoding, "gzip"));
+    let etag = file_etag(len, mtime, precompressed.or_else(|| gzip.then_some("gzip")));
+
+    let mut cache_headers = vec![("ETag", etag.clone())];
+    if let Some(modified) = modified {
+        cache_headers.push(("Last-Modified", http_date(modified)));
+    }
+    if compressible || precompressed.is_some() {
+        cache_headers.push(("Vary", "Accept-Encoding".to_string()));
+    }
+    if let Some(cache_control) = cache_control(path, config) {
+        cache_headers.push(("Cache-Control", cache_control));
+    }
+
+    if not_modified(request, &etag, modified) {
+        print_message(*peer, request, 304);
+        let head = response_head(304, &cache_headers);
+        stream.write_all(head.as_bytes()).unwrap_or_default();
+        return Ok(());
+    }
+
+    let mut headers = vec![
+        (
+            "Content-Type",
+            with_charset(content_type(path), config.charset.as_deref()),
+        ),
+        ("Accept-Ranges", "bytes".to_string()),
+    ];
+    headers.append(&mut cache_headers);
+    if let Some(encoding) = precompressed {
+        headers.push(("Content-Encoding", encoding.to_string()));
+    }
+    if gzip {
+        return serve_gzipped(file, path, stream, peer, config, request, headers);
+    }
+    let range = if_range(request, &etag, modified);
+    let (status, start, length) = match byte_range(range, len) {
+        ByteRange::Full => (200, 0, len),
+        ByteRange::Partial(start, end) => {
+            headers.push(("Content-Range", format!("bytes {start}-{end}/{len}")));
+            (206, start, end - start + 1)
+        }
+        ByteRange::Unsatisfiable => {
+            print_message(*peer, request, 416);
+            range_not_satisfiable(stream, len, request.head_only());
+            return Ok(());
+        }
+    };
+    headers.push(("Content-Length", length.to_string()));
+
+    if start > 0 && file.seek(SeekFrom::Start(start)).is_err() {
+        error_page(stream, config, 500, request.head_only());
+        error!("Could not seek in file: {}", path.display());
+        return Ok(());
+    }
+    // The file is streamed in chunks so memory use doesn't depend on the file size
+    let mut buffer_file = BufReader::with_capacity(FILE_CHUNK_SIZE, file).take(length);
+    print_message(*peer, request, status);
+    let head = response_head(status, &headers);
+    if stream.write_all(head.as_bytes()).is_err() {
+        error!("Could not write header to stream.");
+        return Err(());
+    }
+    if request.method == Method::Head {
+        return Ok(());
+    }
+    if io::copy(&mut buffer_file, stream).is_err() {
+        error!("Error serving file: {}", path.display());
+        return Err(());
+    }
+    Ok(())
+}
+
+fn serve_directory(
+    path: &Path,
+    stream: &mut impl Write,
+    peer: &IpAddr,
+    config: &Config,
+    request: &Request,
+) -> Result<(), ()> {
+    // Relative links on the page would resolve against the parent directory otherwise
+    if !request.path.ends_with('/') {
+        print_message(*peer, request, 301);
+        redirect(stream, 301, &format!("{}/", percent_encode(&request.path)));
+        return Ok(());
+    }
+    // Directories are served by their index file if they have one
+    let index_files: Vec<_> = config
+        .index
+        .iter()
+        .map(|name| path.join(name))
+        .filter(|index_file| index_file.is_file())
+        .collect();
+    if index_files.len() > 1 {
+        REQUEST_HEADERS.with_borrow_mut(|headers| headers.push(("Vary", "Accept".to_string())));
+    }
+    if let Some(index_file) = negotiate_index(index_files, request.header("Accept"))
+        && let Ok(canonical) = index_file.canonicalize()
+    {
+        // The index file goes through the same checks as any other file
+        return serve_local_file(&canonical, stream, peer, config, request, &index_file);
+    }
+    // Well, we can't exactly read a dir so instead we serve a dir listing
+    serve_dir_listing(stream, config, request, path)
+}
+
+// Picks the index file the client likes best. Ties, and clients that don't say, get the first one.
+// Files the client turned down with q=0 are skipped, unless it turned them all down, in which case it gets the first one anyway.
+fn negotiate_index(index_files: Vec<PathBuf>, accept: Option<&str>) -> Option<PathBuf> {
+    let Some(accept) = accept else {
+        return index_files.into_iter().next();
+    };
+    let mut best: Option<(&PathBuf, f32)> = None;
+    for index_file in &index_files {
+        // Types the header doesn't mention at all are still fine as a fallback
+        let quality = match accept_quality(accept, content_type(index_file)) {
+            Some(quality) if quality <= 0.0 => continue,
+            quality => quality.unwrap_or_default(),
+        };
+        if best.is_none_or(|(_, best)| quality > best) {
+            best = Some((index_file, quality));
+        }
+    }
+    best.map(|(index_file, _)| index_file.clone())
+        .or_else(|| index_files.into_iter().next())
+}
+
+// How much the client wants `mime`, going by the most specific range in its Accept header that matches.
+// None if no range matches.
+fn accept_quality(accept: &str, mime: &str) -> Option<f32> {
+    let (kind, _) = mime.split_once('/').unwrap_or((mime, ""));
+    let mut best = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let quality = params
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let specificity = if name.eq_ignore_ascii_case(mime) {
+            3
+        } else if name
+            .strip_suffix("/*")
+            .is_some_and(|range_kind| range_kind.eq_ignore_ascii_case(kind))
+        {
+            2
+        } else if name == "*/*" {
+            1
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(best, _)| specificity > best) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map(|(_, quality)| quality)
+}
+
+fn redirect(stream: &mut impl Write, status: u16, location: &str) {
+    let head = response_head(
+        status,
+        &[
+            ("Location", location.to_string()),
+            ("Content-Length", "0".to_string()),
+        ],
+    );
+    stream.write_all(head.as_bytes()).unwrap_or_default();
+}
+
+fn range_not_satisfiable(stream: &mut impl Write, len: u64, head_only: bool) {
+    let body = error_body(416);
+    let head = response_head(
+        416,
+        &[
+            ("Content-Range", format!("bytes */{len}")),
+            ("Content-Length", body.len().to_string()),
+        ],
+    );
+    let body = if head_only { "" } else { &body };
+    stream
+        .write_all(format!("{head}{body}").as_bytes())
+        .unwrap_or_default();
+}
+
+// Compresses the whole file in memory so the response can still have a Content-Length
+fn serve_gzipped(
+    file: File,
+    path: &Path,
+    stream: &mut impl Write,
+    peer: &IpAddr,
+    config: &Config,
+    request: &Request,
+    mut headers: Vec<(&str, String)>,
+) -> Result<(), ()> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = io::copy(
+        &mut BufReader::with_capacity(FILE_CHUNK_SIZE, file),
+        &mut encoder,
+    )
+    .and_then(|_| encoder.finish());
+    let Ok(body) = compressed else {
+        error_page(stream, config, 500, request.head_only());
+        error!("Could not compress file: {}", path.display());
+        return Ok(());
+    };
+
+    headers.push(("Content-Encoding", "gzip".to_string()));
+    headers.push(("Content-Length", body.len().to_string()));
+    print_message(*peer, request, 200);
+    let head = response_head(200, &headers);
+    if stream.write_all(head.as_bytes()).is_err() {
+        error!("Could not write header to stream.");
+        return Err(());
+    }
+    if request.method == Method::Head {
+        return Ok(());
+    }
+    if stream.write_all(&body).is_err() {
+        error!("Error serving file: {}", path.display());
+        return Err(());
+    }
+    Ok(())
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Links are absolute so they work whether or not the directory was requested with a trailing slash
+fn listing_href(requested_path: &str, name: &str) -> String {
+    format!("{}/{name}", requested_path.trim_end_matches('/'))
+}
+
+const LISTING_DATE_FORMAT: &[BorrowedFormatItem<'_>] =
+    format_description!("[year]-[month]-[day] [hour]:[minute]");
+
+fn listing_entry(
+    requested_path: &str,
+    entry: &fs::DirEntry,
+    is_dir: bool,
+    details: bool,
+) -> String {
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+    // Linking to directories with the trailing slash saves a redirect, and shows them apart from files
+    let (slash, class) = if is_dir { ("/", "dir") } else { ("", "file") };
+    let link = format!(
+        "<a href=\"{}{slash}\">{}{slash}</a>",
+        html_escape(&percent_encode(&listing_href(requested_path, &name))),
+        html_escape(&name)
+    );
+    if !details {
+        return format!("<li class=\"{class}\">{link}</li>");
+    }
+    let metadata = entry.path().metadata().ok();
+    let size = metadata
+        .as_ref()
+        .filter(|_| !is_dir)
+        .map_or_else(|| "-".to_string(), |metadata| human_size(metadata.len()));
+    let modified = metadata
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|modified| {
+            OffsetDateTime::from(modified)
+                .format(LISTING_DATE_FORMAT)
+                .ok()
+        })
+        .unwrap_or_else(|| "-".to_string());
+    format!(
+        "<li class=\"{class} details\">{link}<span>{size}</span><span>{modified} UTC</span></li>"
+    )
+}
+
+// Like "1.5 MiB", with one decimal
+fn human_size(bytes: u64) -> String {
+    let mut tenths = u128::from(bytes) * 10;
+    let mut unit = "B";
+    for next in ["KiB", "MiB", "GiB", "TiB"] {
+        if tenths < 1024 * 10 {
+            break;
+        }
+        tenths /= 1024;
+        unit = next;
+    }
+    if unit == "B" {
+        format!("{bytes} B")
+    } else {
+        format!("{}.{} {unit}", tenths / 10, tenths % 10)
+    }
+}
+
+// Fills in the listing template. The built-in one is checked at compile time and a custom one by
+// `setup_listing_template`, so this can't fail whatever the directory and file names are.
+#[expect(
+    clippy::literal_string_with_formatting_args,
+    reason = "These are the template's placeholders, not format arguments"
+)]
+fn render_listing(template: Option<&str>, directory: &str, lis: &str) -> String {
+    let Some(template) = template else {
+        return format!(
+            include_str!("dirlist.html"),
+            directory = directory,
+            lis = lis
+        );
+    };
+    // A custom template isn't a format string, so CSS braces don't need doubling.
+    // Split on {directory} first so a file named "{lis}" doesn't get replaced too.
+    template
+        .split("{directory}")
+        .map(|part| part.replace("{lis}", lis))
+        .collect::<Vec<_>>()
+        .join(directory)
+}
+
+fn serve_dir_listing(
+    stream: &mut impl Write,
+    config: &Config,
+    request: &Request,
+    actual_path: &Path,
+) -> Result<(), ()> {
+    if config.dir_listing == DirListing::Disabled {
+        debug!(
+            "Not listing {} since directory listings are disabled",
+            actual_path.display()
+        );
+        error_page(stream, config, 404, request.head_only());
+        return Ok(());
+    }
+
+    let requested_path = request.path.as_str();
+    let Ok(entries) = fs::read_dir(actual_path) else {
+        error_page(stream, config, 500, request.head_only());
+        return Ok(());
+    };
+
+    let mut entries = entries
+        .filter_map(Result::ok)
+        // Check against canonicalized path if possible. Otherwise just relative path
+        .filter(|entry| {
+            !config
+                .blacklist
+                .contains(&entry.path().canonicalize().unwrap_or_else(|_| entry.path()))
+        })
+        // They would only 404
+        .filter(|entry| {
+            config.follow_symlinks || !entry.file_type().is_ok_and(|kind| kind.is_symlink())
+        })
+        .filter(|entry| config.dot_files == DotFiles::Serve || !hidden_name(&entry.file_name()))
+        .map(|entry| {
+            // Follows symlinks, like the link will
+            let is_dir = entry.path().is_dir();
+            (entry, is_dir)
+        })
+        .collect::<Vec<_>>();
+    // Directories first, then by name
+    entries.sort_by_cached_key(|(entry, is_dir)| {
+        (!is_dir, entry.file_name().to_string_lossy().to_lowercase())
+    });
+    let negotiated = config.listing_format == ListingFormat::Negotiated;
+    let json = !negotiated || request.header("Accept").is_some_and(accepts_json);
+    if negotiated {
+        REQUEST_HEADERS.with_borrow_mut(|headers| headers.push(("Vary", "Accept".to_string())));
+    }
+    // We made them, so they're always UTF-8
+    let (content_type, dir_list) = if json {
+        ("application/json", json_listing(&entries))
+    } else {
+        (
+            "text/html; charset=utf-8",
+            html_listing(config, requested_path, &entries),
+        )
+    };
+
+    debug!("Serving dir listing of {}", actual_path.display());
+    let head = response_head(
+        200,
+        &[
+            ("Content-Type", content_type.to_string()),
+            // The listing changes whenever the directory does
+            ("Cache-Control", "no-store".to_string()),
+            ("Content-Length", dir_list.len().to_string()),
+        ],
+    );
+    if stream.write_all(head.as_bytes()).is_err() {
+        error!("Could not write header to stream.");
+        return Err(());
+    }
+    if request.method == Method::Head {
+        return Ok(());
+    }
+    if stream.write_all(dir_list.as_ref()).is_err() {
+        error!("Could not write dirlist to stream.");
+        return Err(());
+    }
+
+    Ok(())
+}
+
+fn html_listing(config: &Config, requested_path: &str, entries: &[(fs::DirEntry, bool)]) -> String {
+    let lis = entries
+        .iter()
+        .map(|(entry, is_dir)| {
+            listing_entry(
+                requested_path,
+                entry,
+                *is_dir,
+                config.dir_listing == DirListing::Details,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    render_listing(
+        config.listing_template.as_deref(),
+        &html_escape(requested_path),
+        &lis,
+    )
+}
+
+// An array of {name, is_dir, size, modified}, in the same order as the HTML listing.
+// Directories have no size, and modified is RFC 3339 in UTC, or null if the filesystem doesn't say.
+fn json_listing(entries: &[(fs::DirEntry, bool)]) -> String {
+    let objects = entries
+        .iter()
+        .map(|(entry, is_dir)| {
+            let metadata = entry.path().metadata().ok();
+            let size = metadata
+                .as_ref()
+                .filter(|_| !is_dir)
+                .map_or_else(|| "null".to_string(), |metadata| metadata.len().to_string());
+            let modified = metadata
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| OffsetDateTime::from(modified).format(&Rfc3339).ok())
+                .map_or_else(|| "null".to_string(), |modified| format!("\"{modified}\""));
+            format!(
+                "{{\"name\":\"{}\",\"is_dir\":{is_dir},\"size\":{size},\"modified\":{modified}}}",
+                json_escape(&entry.file_name().to_string_lossy())
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", objects.join(","))
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", u32::from(c));
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const AUTH_REALM: &str = "SimpleWebServer-RS";
+
+fn authorized(request: &Request, credentials: &str) -> bool {
+    let Some(encoded) = request
+        .header("Authorization")
+        .and_then(|auth| auth.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Basic"))
+        .map(|(_, encoded)| encoded.trim())
+    else {
+        return false;
+    };
+    STANDARD
+        .decode(encoded)
+        .is_ok_and(|decoded| constant_time_eq(&decoded, credentials.as_bytes()))
+}
+
+// Looks at every byte no matter where the first difference is, so timing doesn't reveal how much matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn unauthorized_stream(stream: &mut impl Write, head_only: bool) {
+    let body = error_body(401);
+    let head = response_head(
+        401,
+        &[
+            (
+                "WWW-Authenticate",
+                format!("Basic realm=\"{AUTH_REALM}\", charset=\"UTF-8\""),
+            ),
+            ("Content-Length", body.len().to_string()),
+        ],
+    );
+    let body = if head_only { "" } else { &body };
+    stream
+        .write_all(format!("{head}{body}").as_bytes())
+        .unwrap_or_default();
+    stream.flush().unwrap_or_default();
+}
+
+// Answers a health check without touching the filesystem
+fn health_stream(stream: &mut impl Write, request: &Request) -> bool {
+    let body = "ok\n";
+    let head = response_head(
+        200,
+        &[
+            ("Content-Type", "text/plain".to_string()),
+            ("Cache-Control", "no-store".to_string()),
+            ("Content-Length", body.len().to_string()),
+        ],
+    );
+    let response = if request.method == Method::Head {
+        head
+    } else {
+        head + body
+    };
+    stream.write_all(response.as_bytes()).is_ok() && stream.flush().is_ok()
+}
+
+// Only there with --admin-token
+const ADMIN_SHUTDOWN_PATH: &str = "/admin/shutdown";
+
+// The token gets its own header so it can be sent along with --auth's Authorization
+fn admin_shutdown(
+    stream: &mut impl Write,
+    request: &Request,
+    peer: IpAddr,
+    config: &Config,
+    token: &str,
+) {
+    // Either way the connection ends here, so the client shouldn't send anything else on it
+    CLOSES_CONNECTION.set(true);
+    if !request
+        .header("X-Admin-Token")
+        .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+    {
+        warn!(
+            "Shutdown request from {} with a wrong admin token.",
+            log_ip(peer)
+        );
+        print_message(peer, request, 403);
+        error_page(stream, config, 403, request.head_only());
+        return;
+    }
+    info!("Shutdown requested by {}.", log_ip(peer));
+    print_message(peer, request, 202);
+    let body = "shutting down\n";
+    let head = response_head(
+        202,
+        &[
+            ("Content-Type", "text/plain".to_string()),
+            ("Content-Length", body.len().to_string()),
+        ],
+    );
+    let body = if request.head_only() { "" } else { body };
+    stream
+        .write_all(format!("{head}{body}").as_bytes())
+        .unwrap_or_default();
+    stream.flush().unwrap_or_default();
+    config.shutdown.store(true, Ordering::Relaxed);
+}
+
+fn method_not_allowed_stream(stream: &mut impl Write) {
+    let body = error_body(405);
+    let head = response_head(
+        405,
+        &[
+            ("Allow", ALLOWED_METHODS.to_string()),
+            ("Content-Length", body.len().to_string()),
+        ],
+    );
+    stream
+        .write_all(format!("{head}{body}").as_bytes())
+        .unwrap_or_default();
+    stream.flush().unwrap_or_default();
+}
+
+fn options_stream(stream: &mut impl Write) -> bool {
+    // 204s can't have a body, so no Content-Length either
+    let head = response_head(204, &[("Allow", ALLOWED_METHODS.to_string())]);
+    stream.write_all(head.as_bytes()).is_ok() && stream.flush().is_ok()
+}
+
+#[derive(Clone)]
+struct Cors {
+    // May contain "*"
+    origins: Vec<String>,
+    // Like "GET, HEAD, OPTIONS"
+    methods: String,
+}
+
+impl Cors {
+    fn headers(&self, request: &Request) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if self.origins.iter().any(|origin| origin == "*") {
+            headers.push(("Access-Control-Allow-Origin", "*".to_string()));
+        } else {
+            // The answer depends on who is asking, so caches have to keep them apart
+            headers.push(("Vary", "Origin".to_string()));
+            match request.header("Origin") {
+                Some(origin) if self.origins.iter().any(|allowed| allowed == origin) => {
+                    headers.push(("Access-Control-Allow-Origin", origin.to_string()));
+                }
+                // Not allowed, so the browser gets nothing and blocks the response
+                _ => return headers,
+            }
+        }
+        // Preflight
+        if request.method == Method::Options
+            && request.header("Access-Control-Request-Method").is_some()
+        {
+            headers.push(("Access-Control-Allow-Methods", self.methods.clone()));
+            if let Some(requested) = request.header("Access-Control-Request-Headers") {
+                headers.push(("Access-Control-Allow-Headers", requested.to_string()));
+            }
+        }
+        headers
+    }
+}
+
+// Passes a connection through while taking note of the response's status and body size for the access log
+struct ResponseRecorder<S> {
+    inner: S,
+    head: Vec<u8>,
+    head_done: bool,
+    body_bytes: u64,
+}
+
+impl<S> ResponseRecorder<S> {
+    const fn new(inner: S) -> Self {
+        Self {
+            inner,
+            head: Vec::new(),
+            head_done: false,
+            body_bytes: 0,
+        }
+    }
+
+    // None if nothing was sent
+    fn status(&self) -> Option<u16> {
+        self.head
+            .get(9..12)
+            .and_then(|status| str::from_utf8(status).ok())
+            .and_then(|status| status.parse().ok())
+    }
+
+    fn record(&mut self, written: &[u8]) {
+        if self.head_done {
+            self.body_bytes += written.len() as u64;
+            return;
+        }
+        self.head.extend_from_slice(written);
+        if let Some(end) = header_end(&self.head) {
+            self.body_bytes = (self.head.len() - end) as u64;
+            self.head.truncate(end);
+            self.head_done = true;
+        }
+    }
+}
+
+impl<S: Read> Read for ResponseRecorder<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for ResponseRecorder<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.record(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+const CLF_DATE_FORMAT: &[BorrowedFormatItem<'_>] = format_description!(
+    "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+);
+
+// host ident authuser [date] "request" status bytes
+fn log_access(
+    access_log: &Mutex<File>,
+    peer: IpAddr,
+    request: Option<&Request>,
+    config: &Config,
+    status: u16,
+    body_bytes: u64,
+) {
+    let user = config
+        .auth
+        .as_deref()
+        .filter(|_| request.is_some() && status != 401)
+        .and_then(|credentials| credentials.split_once(':'))
+        .map_or("-", |(user, _)| user);
+    let line = request.map_or_else(
+        || "-".to_string(),
+        |request| log_safe(&request.line).replace('"', "\\\""),
+    );
+    let date = OffsetDateTime::now_utc()
+        .format(CLF_DATE_FORMAT)
+        .unwrap_or_default();
+    let bytes = if body_bytes == 0 {
+        "-".to_string()
+    } else {
+        body_bytes.to_string()
+    };
+    let Ok(mut file) = access_log.lock() else {
+        error!("Access log lock is poisoned.");
+        return;
+    };
+    let peer = log_ip(peer);
+    if writeln!(file, "{peer} - {user} [{date}] \"{line}\" {status} {bytes}").is_err() {
+        error!("Could not write to the access log.");
+    }
+}
+
+// Serves requests until the client or the idle timeout closes the connection.
+// `socket` is only used to change the read timeout and tell TCP clients apart, since `stream` may be wrapped in TLS.
+fn handle_client(
+    stream: &mut (impl Read + Write),
+    socket: &Connection,
+    peer: IpAddr,
+    config: &Config,
+) {
+    let mut buffer = Vec::new();
+    if socket
+        .set_read_timeout(Some(config.header_timeout))
+        .is_err()
+    {
+        error!("Could not set a read timeout for {}.", log_ip(peer));
+        return;
+    }
+    let mut served = 1;
+    while handle_request(
+        stream,
+        &mut buffer,
+        peer,
+        socket.is_tcp(),
+        config,
+        served == config.max_keep_alive_requests,
+    ) {
+        served = served.saturating_add(1);
+        if socket
+            .set_read_timeout(Some(config.keep_alive_timeout))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+// Returns whether the connection can be used for another request.
+// `last` is set once the connection has served as many requests as it's allowed to.
+fn handle_request(
+    stream: &mut (impl Read + Write),
+    buffer: &mut Vec<u8>,
+    peer: IpAddr,
+    tcp: bool,
+    config: &Config,
+    last: bool,
+) -> bool {
+    let mut recorder = ResponseRecorder::new(stream);
+    let request = get_path(
+        &mut recorder,
+        &peer,
+        buffer,
+        config.header_timeout,
+        config.max_header_bytes,
+        config.max_uri_length,
+    );
+    let mut client = peer;
+    if request.is_some() {
+        config.metrics.requests.fetch_add(1, Ordering::Relaxed);
+    }
+    let keep_alive = request.as_ref().is_some_and(|request| {
+        client = config.client_ip(peer, request);
+        let mut headers = config
+            .cors
+            .as_ref()
+            .map(|cors| cors.headers(request))
+            .unwrap_or_default();
+        // A 1.0 client assumes the connection closes unless it hears otherwise, a 1.1 one the opposite
+        let persistent = !last && request.keep_alive() && !config.keep_alive_timeout.is_zero();
+        if request.http_1_0() && persistent {
+            headers.push(("Connection", "keep-alive".to_string()));
+        }
+        CLOSES_CONNECTION.set(!persistent);
+        REQUEST_HEADERS.set(headers);
+        // Every request counts, or keep-alive would let a client send as many as it likes on one connection.
+        // Unix socket clients aren't rate-limited by address, since they all look like 127.0.0.1.
+        let keep_alive = if (tcp || config.trusts(peer))
+            && let Some(left) = config.ratelimited(client)
+        {
+            // A proxy may have other clients' requests queued up on this connection, so keep it open
+            print_message(client, request, 429);
+            let json = JSON_ERRORS.load(Ordering::Relaxed)
+                || request.header("Accept").is_some_and(accepts_json);
+            retry_later_stream(&mut recorder, 429, left, json, request.head_only());
+            persistent
+        } else if let Some(site) = config.site(request) {
+            respond(&mut recorder, request, client, site)
+        } else {
+            print_message(client, request, 404);
+            error_stream(&mut recorder, 404, request.head_only());
+            persistent
+        };
+        REQUEST_HEADERS.take();
+        keep_alive && !last
+    });
+    RESPONSE_VERSION.set("1.1");
+    CLOSES_CONNECTION.set(true);
+    if let Some(status) = recorder.status() {
+        config.metrics.record_response(status, recorder.body_bytes);
+        if let Some(access_log) = &config.access_log {
+            log_access(
+                access_log,
+                client,
+                request.as_ref(),
+                config,
+                status,
+                recorder.body_bytes,
+            );
+        }
+    }
+    keep_alive
+}
+
+fn respond(stream: &mut impl Write, request: &Request, peer: IpAddr, config: &Config) -> bool {
+    let keep_alive = request.keep_alive() && !config.keep_alive_timeout.is_zero();
+    // Load balancers don't log in, so this comes before authentication
+    if config.health_path.as_deref() == Some(request.path.as_str()) {
+        print_message(peer, request, 200);
+        return health_stream(stream, request) && keep_alive;
+    }
+    // Says nothing about the files, and CORS preflights never carry credentials
+    if request.method == Method::Options {
+        print_message(peer, request, 204);
+        return options_stream(stream) && keep_alive;
+    }
+    // Has its own credentials, so it doesn't need --auth's too
+    if let Some(token) = &config.admin_token
+        && request.path == ADMIN_SHUTDOWN_PATH
+    {
+        admin_shutdown(stream, request, peer, config, token);
+        return false;
+    }
+    // The new location says nothing about the files, so this comes before authentication too
+    if let Some((status, location)) = config.redirects.get(&request.path) {
+        print_message(peer, request, *status);
+        redirect(stream, *status, location);
+        return keep_alive;
+    }
+    if let Some(credentials) = &config.auth
+        && !authorized(request, credentials)
+    {
+        print_message(peer, request, 401);
+        unauthorized_stream(stream, request.head_only());
+        return keep_alive;
+    }
+    if config.metrics_path.as_deref() == Some(request.path.as_str()) {
+        print_message(peer, request, 200);
+        return metrics_stream(stream, request, &config.metrics) && keep_alive;
+    }
+    let requested_path = request.path.as_str();
+
+    // Testing if the path exists
+    let complete = if let Some(file) = &config.file {
+        serve_file(file, stream, &peer, config, request).is_ok()
+    } else {
+        match server_path_to_local_path(requested_path, &config.root) {
+            Ok((path, abpath)) => {
+                serve_local_file(&path, stream, &peer, config, request, &abpath).is_ok()
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound
+                        | io::ErrorKind::NotADirectory
+                        // Names too long for the filesystem or with NUL bytes in them can't exist either
+                        | io::ErrorKind::InvalidFilename
+                        | io::ErrorKind::InvalidInput
+                ) =>
+            {
+                error_page(stream, config, 404, request.head_only());
+                print_message(peer, request, 404);
+                true
+            }
+            // Permission problems and the like shouldn't pass for missing files
+            Err(e) => {
+                error!("Could not look up {}: {e}", log_safe(requested_path));
+                error_page(stream, config, 500, request.head_only());
+                print_message(peer, request, 500);
+                true
+            }
+        }
+    };
+    complete && stream.flush().is_ok() && keep_alive
+}
+
+// Wraps the connection in TLS if needed, handles it and closes it
+fn serve_connection(mut stream: Connection, config: &Config) {
+    let _id = ConnectionId::start();
+    let _active = config.metrics.connection();
+    // The peer may already be gone. Dropping the stream closes our end too.
+    let Ok(peer) = stream.peer_ip() else {
+        warn!("Could not get peer IP address. Closing connection.");
+        return;
+    };
+
+    if let Some(tls) = &config.tls {
+        let Ok(connection) = ServerConnection::new(Arc::clone(tls)) else {
+            error!("Could not start TLS connection with {}.", log_ip(peer));
+            return;
+        };
+        let Ok(socket) = stream.try_clone() else {
+            error!("Could not clone the connection with {}.", log_ip(peer));
+            return;
+        };
+        let mut tls_stream = StreamOwned::new(connection, stream);
+        handle_client(&mut tls_stream, &socket, peer, config);
+        tls_stream.conn.send_close_notify();
+        tls_stream.flush().unwrap_or_default();
+        tls_stream.sock.shutdown(Shutdown::Both).unwrap_or_default();
+    } else {
+        let Ok(socket) = stream.try_clone() else {
+            error!("Could not clone the connection with {}.", log_ip(peer));
+            return;
+        };
+        handle_client(&mut stream, &socket, peer, config);
+        stream.shutdown(Shutdown::Both).unwrap_or_default();
+    }
+}
+
+fn setup_tls(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(Iterator::collect)
+        .map_err(|e| {
+            io::Error::other(format!(
+                "Could not read certificate {}: {e}",
+                cert.display()
+            ))
+        })?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| {
+        io::Error::other(format!("Could not read private key {}: {e}", key.display()))
+    })?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map(Arc::new)
+        .map_err(|e| io::Error::other(format!("Invalid certificate or key: {e}")))
+}
+
+thread_local! {
+    // Set while a connection is handled, so every log line about it can be told apart from concurrent ones
+    static CONNECTION_ID: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+// Marks the log lines of this thread with a new connection ID until dropped
+struct ConnectionId;
+
+impl ConnectionId {
+    fn start() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        CONNECTION_ID.set(Some(NEXT.fetch_add(1, Ordering::Relaxed)));
+        Self
+    }
+}
+
+impl Drop for ConnectionId {
+    fn drop(&mut self) {
+        CONNECTION_ID.set(None);
+    }
+}
+
+// Prefixes log lines written while handling a connection with its ID, like `[#12] 127.0.0.1: GET / - 200`
+struct ConnectionLogger(Box<dyn SharedLogger>);
+
+impl log::Log for ConnectionLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        let Some(id) = CONNECTION_ID.get() else {
+            self.0.log(record);
+            return;
+        };
+        self.0.log(
+            &log::Record::builder()
+                .args(format_args!("[#{id}] {}", record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+fn setup_logger(cli: &Cli) {
+    let logconfig = ConfigBuilder::new()
+        .set_time_format_custom(format_description!(version = 2, "[weekday repr:short] [month repr:short] [day] [hour repr:12]:[minute]:[second] [period case:upper] [year repr:full]"))
+        .build();
+
+    let clilevel = if cli.quiet {
+        LevelFilter::Off
+    } else if cli.verbose {
+        LevelFilter::Trace
+    } else {
+        LevelFilter::Info
+    };
+
+    let logger: Box<dyn SharedLogger> = if cli.enablelogfiles {
+        CombinedLogger::new(vec![
+            TermLogger::new(
+                clilevel,
+                logconfig.clone(),
+                TerminalMode::Mixed,
+                ColorChoice::Auto,
+            ),
+            WriteLogger::new(
+                LevelFilter::Debug,
+                logconfig.clone(),
+                open_log_file(&cli.logfile, cli.truncate_logs),
+            ),
+            WriteLogger::new(
+                LevelFilter::Trace,
+                logconfig,
+                open_log_file(&cli.full_logfile, cli.truncate_logs),
+            ),
+        ])
+    } else if !cli.quiet {
+        TermLogger::new(clilevel, logconfig, TerminalMode::Mixed, ColorChoice::Auto)
+    } else {
+        return;
+    };
+    log::set_max_level(logger.level());
+    log::set_boxed_logger(Box::new(ConnectionLogger(logger))).expect("Could not start logger");
+}
+
+// Logs from earlier runs are kept unless asked otherwise. The logger isn't running yet, so failing is all we can do.
+fn open_log_file(path: &Path, truncate: bool) -> File {
+    File::options()
+        .create(true)
+        .write(true)
+        .append(!truncate)
+        .truncate(truncate)
+        .open(path)
+        .expect("Could not create log file")
+}
+
+fn setup_file(file: &Path) -> io::Result<PathBuf> {
+    match file.canonicalize() {
+        Ok(canon) if canon.is_file() => {
+            info!("Serving file: {}", canon.display());
+            Ok(canon)
+        }
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file.", file.display()),
+        )),
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("Could not find file {}: {e}", file.display()),
+        )),
+    }
+}
+
+fn setup_root(root: &Path, strict_permissions: bool) -> io::Result<PathBuf> {
+    let canon = match root.canonicalize() {
+        Ok(canon) if canon.is_dir() => canon,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("Web root {} is not a directory.", root.display()),
+            ));
+        }
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("Could not find web root {}: {e}", root.display()),
+            ));
+        }
+    };
+    info!("Web root: {}", canon.display());
+    // Anyone on the machine could swap files out between our checks and serving them
+    if world_writable(&canon) {
+        if strict_permissions {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Web root {} is world-writable. Refusing to serve it with --strict-permissions.",
+                    canon.display()
+                ),
+            ));
+        }
+        warn!(
+            "Web root {} is world-writable, so anyone on this machine can change what gets served.",
+            canon.display()
+        );
+    }
+    Ok(canon)
+}
+
+#[cfg(unix)]
+fn world_writable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o002 != 0)
+}
+
+// Windows permissions are ACLs, which don't boil down to a single bit
+#[cfg(not(unix))]
+const fn world_writable(_path: &Path) -> bool {
+    false
+}
+
+fn setup_blacklist(
+    blist: Option<Vec<String>>,
+    root: &Path,
+    log_files: [&Path; 2],
+    normalizedblist: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    info!("Parsing blacklist...");
+    // Blacklisted files are relative to the web root, but the log files can be anywhere
+    let Some(mut blist) = blist else {
+        for log_file in log_files {
+            // The log files don't exist unless --enablelogfiles is passed
+            let path = log_file
+                .canonicalize()
+                .or_else(|_| absolute(log_file))
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Could not find log file {}.", log_file.display()),
+                    )
+                })?;
+            normalizedblist.push(path);
+        }
+        return Ok(());
+    };
+
+    // Allow for empty blacklist with -b ""
+    if blist.contains(&String::new()) && blist.len() == 1 {
+        blist.pop();
+    }
+
+    for b in &blist {
+        normalizedblist.push(root.join(b));
+    }
+    Ok(())
+}
+
+// Fails if the template can't be read or has nowhere to put the entries
+fn setup_listing_template(path: &Path) -> io::Result<String> {
+    let template = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Could not read listing template {}: {e}", path.display()),
+        )
+    })?;
+    if !template.contains("{lis}") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Listing template {} has no {{lis}} placeholder for the entries.",
+                path.display()
+            ),
+        ));
+    }
+    if !template.contains("{directory}") {
+        warn!(
+            "Listing template {} has no {{directory}} placeholder, so listings won't say which directory they are.",
+            path.display()
+        );
+    }
+    Ok(template)
+}
+
+// Fails if the file can't be opened. The log is blacklisted so it can't be downloaded.
+fn setup_access_log(path: &Path, normalizedblist: &mut Vec<PathBuf>) -> io::Result<Mutex<File>> {
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Could not open access log {}: {e}", path.display()),
+            )
+        })?;
+    if let Ok(canonical) = path.canonicalize() {
+        normalizedblist.push(canonical);
+    }
+    Ok(Mutex::new(file))
+}
+
+fn retry_later_stream(
+    stream: &mut impl Write,
+    status: u16,
+    retry_after: u64,
+    json: bool,
+    head_only: bool,
+) {
+    let mut headers = vec![("Retry-After", retry_after.to_string())];
+    let body = if json && !QUIET_ERRORS.load(Ordering::Relaxed) {
+        let error = if status == 429 {
+            "rate_limited"
+        } else {
+            "unavailable"
+        };
+        headers.push(("Content-Type", "application/json".to_string()));
+        format!("{{\"error\":\"{error}\",\"retry_after\":{retry_after}}}")
+    } else {
+        error_body(status)
+    };
+    headers.push(("Content-Length", body.len().to_string()));
+    let head = response_head(status, &headers);
+    let body = if head_only { "" } else { &body };
+    stream
+        .write_all(format!("{head}{body}").as_bytes())
+        .unwrap_or_default();
+    stream.flush().unwrap_or_default();
+}
+
+// Connections are turned away before their request is read, so this only looks at what
+// has already arrived. A client that hasn't sent its Accept header yet gets the default.
+fn wants_json(stream: &Connection) -> bool {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        return true;
+    }
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut buf = vec![0; DEFAULT_MAX_HEADER_BYTES];
+    let arrived = stream.peek(&mut buf).unwrap_or_default();
+    stream.set_nonblocking(false).unwrap_or_default();
+    String::from_utf8_lossy(&buf[..arrived])
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("Accept"))
+        .any(|(_, value)| accepts_json(value))
+}
+
+fn accepts_json(accept: &str) -> bool {
+    accept.split(',').any(|media| {
+        media
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .eq_ignore_ascii_case("application/json")
+    })
+}
+
+// Closing a socket with unread data makes the kernel send a RST, which can throw away
+// a response the client hasn't read yet. Rejections happen before we read the request,
+// so drain whatever has already arrived without blocking the accept loop.
+fn discard_request(stream: &mut Connection) {
+    stream.shutdown(Shutdown::Write).unwrap_or_default();
+    if stream.set_nonblocking(true).is_err() {
+        return;
+    }
+    let mut buf = [0; 1024];
+    let mut discarded = 0;
+    while discarded < DEFAULT_MAX_HEADER_BYTES {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => discarded += n,
+        }
+    }
+}
+
+// Seconds a client is told to wait when we are at --max-connections, or it is at --max-connections-per-ip
+const CONNECTION_LIMIT_RETRY_AFTER: u64 = 5;
+
+type OpenPerIp = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Counts open connections so we can turn new ones away at the limit
+struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    /// 0 means no limit
+    max: usize,
+    /// Only tracked if there is a per-IP limit
+    open_per_ip: OpenPerIp,
+    /// 0 means no limit
+    max_per_ip: usize,
+}
+
+/// Why a connection couldn't get a slot
+#[derive(PartialEq, Eq)]
+enum Rejection {
+    /// The server is at --max-connections
+    Full,
+    /// The client is at --max-connections-per-ip
+    ClientFull,
+}
+
+/// Held for as long as a connection is open. Dropping it frees the slot.
+struct ConnectionSlot {
+    open: Arc<AtomicUsize>,
+    /// Set if the connection counts towards its client's limit too
+    client: Option<(IpAddr, OpenPerIp)>,
+}
+
+impl ConnectionLimit {
+    fn new(max: usize, max_per_ip: usize) -> Self {
+        Self {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+            open_per_ip: Arc::new(Mutex::new(HashMap::new())),
+            max_per_ip,
+        }
+    }
+
+    // `client` is None for connections that shouldn't count towards a per-IP limit, like ones from trusted proxies
+    fn acquire(&self, client: Option<IpAddr>) -> Result<ConnectionSlot, Rejection> {
+        // IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+        let client = client
+            .filter(|_| self.max_per_ip != 0)
+            .map(|client| client.to_canonical());
+        // Held until the slot is taken, so two connections can't both take the client's last one
+        let mut open_per_ip = match client {
+            Some(_) => Some(self.open_per_ip.lock().map_err(|_| {
+                error!("Connection count lock is poisoned.");
+                Rejection::Full
+            })?),
+            None => None,
+        };
+        if let (Some(client), Some(open_per_ip)) = (client, &open_per_ip)
+            && open_per_ip
+                .get(&client)
+                .is_some_and(|&open| open >= self.max_per_ip)
+        {
+            return Err(Rejection::ClientFull);
+        }
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (self.max == 0 || open < self.max).then_some(open + 1)
+            })
+            .map_err(|_| Rejection::Full)?;
+        if let (Some(client), Some(open_per_ip)) = (client, &mut open_per_ip) {
+            *open_per_ip.entry(client).or_default() += 1;
+        }
+        Ok(ConnectionSlot {
+            open: Arc::clone(&self.open),
+            client: client.map(|client| (client, Arc::clone(&self.open_per_ip))),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+        if let Some((client, open_per_ip)) = &self.client
+            && let Ok(mut open_per_ip) = open_per_ip.lock()
+            && let Some(open) = open_per_ip.get_mut(client)
+        {
+            *open -= 1;
+            // Forget clients once they're gone so the map doesn't grow forever
+            if *open == 0 {
+                open_per_ip.remove(client);
+            }
+        }
+    }
+}
+
+// An overloaded server can turn away thousands of connections a second, so they are summed up at most this often
+const OVERLOAD_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long the accept loop waits for a connection before checking whether rejections are due to be logged
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Warns about connections turned away for lack of capacity, without flooding the log
+struct OverloadWarning {
+    // Since the last warning
+    rejected: u64,
+    // Why the latest one was rejected
+    reason: &'static str,
+    last_warning: Option<Instant>,
+}
+
+impl OverloadWarning {
+    const fn new() -> Self {
+        Self {
+            rejected: 0,
+            reason: "",
+            last_warning: None,
+        }
+    }
+
+    fn reject(&mut self, config: &Config, reason: &'static str) {
+        config.metrics.overloaded.fetch_add(1, Ordering::Relaxed);
+        self.rejected += 1;
+        self.reason = reason;
+        self.flush();
+    }
+
+    // Warns about the rejections since the last warning once it's been long enough.
+    // Called while the server is idle too, so the end of a burst doesn't wait for the next connection to be logged.
+    fn flush(&mut self) {
+        if self
+            .last_warning
+            .is_none_or(|last| last.elapsed() >= OVERLOAD_WARNING_INTERVAL)
+        {
+            self.warn();
+        }
+    }
+
+    fn warn(&mut self) {
+        if self.rejected == 0 {
+            return;
+        }
+        warn!(
+            "Server is at capacity ({}). Rejected {} connection(s) since the last warning.",
+            self.reason, self.rejected
+        );
+        self.rejected = 0;
+        self.last_warning = Some(Instant::now());
+    }
+}
+
+/// Counters for the metrics endpoint, shared by all handlers
+#[derive(Default)]
+struct Metrics {
+    requests: AtomicU64,
+    // Keyed by status code
+    responses: Mutex<BTreeMap<u16, u64>>,
+    // Response bodies only
+    bytes_sent: AtomicU64,
+    active_connections: AtomicU64,
+    // Requests turned away by the rate limiter
+    ratelimited: AtomicU64,
+    // Connections turned away because we were at capacity
+    overloaded: AtomicU64,
+}
+
+/// Counts as an active connection until dropped
+struct ActiveConnection<'a>(&'a AtomicU64);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    fn connection(&self) -> ActiveConnection<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(&self.active_connections)
+    }
+
+    fn record_response(&self, status: u16, body_bytes: u64) {
+        self.bytes_sent.fetch_add(body_bytes, Ordering::Relaxed);
+        if let Ok(mut responses) = self.responses.lock() {
+            *responses.entry(status).or_default() += 1;
+        }
+    }
+
+    // Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(String, u64)]| {
+            let _ = writeln!(text, "# HELP simplewebserver_{name} {help}");
+            let _ = writeln!(text, "# TYPE simplewebserver_{name} {kind}");
+            for (labels, value) in values {
+                let _ = writeln!(text, "simplewebserver_{name}{labels} {value}");
+            }
+        };
+        let single = |counter: &AtomicU64| [(String::new(), counter.load(Ordering::Relaxed))];
+        metric(
+            "requests_total",
+            "counter",
+            "Requests received.",
+            &single(&self.requests),
+        );
+        let responses = self
+            .responses
+            .lock()
+            .map(|responses| {
+                responses
+                    .iter()
+                    .map(|(status, count)| (format!("{{status=\"{status}\"}}"), *count))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        metric(
+            "responses_total",
+            "counter",
+            "Responses sent, by status code.",
+            &responses,
+        );
+        metric(
+            "sent_bytes_total",
+            "counter",
+            "Bytes of response bodies sent.",
+            &single(&self.bytes_sent),
+        );
+        metric(
+            "active_connections",
+            "gauge",
+            "Connections being handled right now.",
+            &single(&self.active_connections),
+        );
+        metric(
+            "ratelimited_total",
+            "counter",
+            "Requests turned away by the rate limiter.",
+            &single(&self.ratelimited),
+        );
+        metric(
+            "overloaded_total",
+            "counter",
+            "Connections turned away because all workers were busy or --max-connections was reached.",
+            &single(&self.overloaded),
+        );
+        text
+    }
+}
+
+fn metrics_stream(stream: &mut impl Write, request: &Request, metrics: &Metrics) -> bool {
+    let body = metrics.render();
+    let head = response_head(
+        200,
+        &[
+            ("Content-Type", "text/plain; version=0.0.4".to_string()),
+            ("Cache-Control", "no-store".to_string()),
+            ("Content-Length", body.len().to_string()),
+        ],
+    );
+    let response = if request.method == Method::Head {
+        head
+    } else {
+        head + &body
+    };
+    stream.write_all(response.as_bytes()).is_ok() && stream.flush().is_ok()
+}
+
+/// A `--vhost` mapping, like `example.com=/srv/example`
+#[derive(Clone, Debug)]
+struct VirtualHost {
+    host: String,
+    root: PathBuf,
+}
+
+impl FromStr for VirtualHost {
+    type Err = String;
+
+    fn from_str(vhost: &str) -> Result<Self, Self::Err> {
+        match vhost.split_once('=') {
+            Some((host, root)) if !host.is_empty() && !root.is_empty() => Ok(Self {
+                host: host.to_ascii_lowercase(),
+                root: PathBuf::from(root),
+            }),
+            _ => Err(format!("expected HOST=DIR, got: {vhost}")),
+        }
+    }
+}
+
+/// A `--redirect` mapping, like `/old.html=/new.html`
+#[derive(Clone, Debug)]
+struct Redirect {
+    from: String,
+    to: String,
+}
+
+impl FromStr for Redirect {
+    type Err = String;
+
+    fn from_str(redirect: &str) -> Result<Self, Self::Err> {
+        match redirect.split_once('=') {
+            Some((from, to)) if from.starts_with('/') && !to.is_empty() => Ok(Self {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err(format!(
+                "expected PATH=URL with PATH starting with /, got: {redirect}"
+            )),
+        }
+    }
+}
+
+// The Host header without the port, like "example.com" or "[::1]"
+fn host_name(host: &str) -> &str {
+    let end = if host.starts_with('[') {
+        host.find(']').map_or(host.len(), |end| end + 1)
+    } else {
+        host.find(':').unwrap_or(host.len())
+    };
+    &host[..end]
+}
+
+/// A network in CIDR notation, like `10.0.0.0/8`. A bare address is a network of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct IpNet {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = cidr.split_once('/').unwrap_or((cidr, ""));
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid IP address: {address}"))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max_prefix
+        } else {
+            prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length: {prefix}"))?
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max_prefix = if self.network.is_ipv4() { 32 } else { 128 };
+        if self.prefix == max_prefix {
+            write!(f, "{}", self.network)
+        } else {
+            write!(f, "{}/{}", self.network, self.prefix)
+        }
+    }
+}
+
+impl IpNet {
+    // Widened to what `log_ip` would show if it's any narrower
+    fn for_log(self) -> Self {
+        if !LOG_IP_PRIVACY.load(Ordering::Relaxed) {
+            return self;
+        }
+        let prefix = if self.network.is_ipv4() { 24 } else { 48 };
+        Self::around(self.network, self.prefix.min(prefix))
+    }
+
+    // The network of the given size that `ip` is in
+    fn around(ip: IpAddr, prefix: u8) -> Self {
+        let network = match ip.to_canonical() {
+            IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & Self::v4_mask(prefix)).into()),
+            IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & Self::v6_mask(prefix)).into()),
+        };
+        Self { network, prefix }
+    }
+
+    fn v4_mask(prefix: u8) -> u32 {
+        u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+    }
+
+    fn v6_mask(prefix: u8) -> u128 {
+        u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = Self::v4_mask(self.prefix);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = Self::v6_mask(self.prefix);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    // The denylist wins over the allowlist. An empty allowlist allows everyone.
+    fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+    }
+}
+
+// Requests are counted over a sliding window of this length
+const RATELIMIT_WINDOW: Duration = Duration::from_mins(1);
+
+// Rounds up, so clients that wait for Retry-After aren't let back too early and limited again
+fn secs_left(left: Duration) -> u64 {
+    left.as_secs() + u64::from(left.subsec_nanos() > 0)
+}
+
+struct RateLimiter {
+    ratelimit: u16,
+    timeout: Duration,
+    // Clients are counted together by network, since one client can easily own a whole IPv6 /64
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    // When each network made its requests in the current window
+    requests: HashMap<IpNet, VecDeque<Instant>>,
+    // When each rate-limited network is allowed back
+    ratelimits: HashMap<IpNet, Instant>,
+    last_cleanup: Instant,
+}
+
+impl RateLimiter {
+    fn new(cli: &Cli) -> Self {
+        Self {
+            ratelimit: cli.ratelimit,
+            timeout: cli.timeout_ms.map_or_else(
+                || Duration::from_secs(cli.timeout.into()),
+                Duration::from_millis,
+            ),
+            ipv4_prefix: cli.ratelimit_ipv4_prefix,
+            ipv6_prefix: cli.ratelimit_ipv6_prefix,
+            requests: HashMap::new(),
+            ratelimits: HashMap::new(),
+            last_cleanup: Instant::now(),
+        }
+    }
+
+    // Returns None to allow the request, or the seconds left on the ratelimit to block it.
+    // Without `count` the request is only checked against a running timeout, and not counted.
+    fn check(&mut self, ip: IpAddr, count: bool) -> Option<u64> {
+        let now = Instant::now();
+        self.cleanup(now);
+
+        let network = if ip.to_canonical().is_ipv4() {
+            IpNet::around(ip, self.ipv4_prefix)
+        } else {
+            IpNet::around(ip, self.ipv6_prefix)
+        };
+
+        // Requests made while in timeout aren't counted, so they can't extend it
+        if let Some(&until) = self.ratelimits.get(&network) {
+            if now > until {
+                // Start counting from scratch once the timeout is over
+                self.ratelimits.remove(&network);
+                self.requests.remove(&network);
+            } else {
+                let left = secs_left(until - now);
+                debug!(
+                    "Rejecting request from rate-limited network: {}. {left} secs left on ratelimit.",
+                    network.for_log()
+                );
+                return Some(left);
+            }
+        }
+        if !count {
+            return None;
+        }
+
+        let window = self.requests.entry(network).or_default();
+        while window
+            .front()
+            .is_some_and(|&time| now - time >= RATELIMIT_WINDOW)
+        {
+            window.pop_front();
+        }
+        window.push_back(now);
+
+        if window.len() >= self.ratelimit.into() {
+            warn!(
+                "Rate limiting {} after {} requests in a minute.",
+                network.for_log(),
+                window.len()
+            );
+            self.requests.remove(&network);
+            let until = now.checked_add(self.timeout).unwrap_or_else(|| {
+                error!("Could not calculate when ratelimit should expire???");
+                // Just let the next request through I guess?
+                now
+            });
+            self.ratelimits.insert(network, until);
+
+            let left = secs_left(until - now);
+            debug!(
+                "Rejecting request from rate-limited network: {}. {left} secs left on ratelimit.",
+                network.for_log()
+            );
+            return Some(left);
+        }
+        None
+    }
+
+    // Forgets IPs that have gone quiet so the maps don't grow forever
+    fn cleanup(&mut self, now: Instant) {
+        if now - self.last_cleanup < RATELIMIT_WINDOW {
+            return;
+        }
+        self.last_cleanup = now;
+        self.requests.retain(|_, window| {
+            window
+                .back()
+                .is_some_and(|&time| now - time < RATELIMIT_WINDOW)
+        });
+        self.ratelimits.retain(|_, until| *until > now);
+        trace!("Cleaned up ratelimit state.");
+    }
+}
+
+// Starts the worker pool. Accepted streams are sent through the returned channel.
+fn spawn_workers(
+    workers: NonZero<usize>,
+    config: &Arc<Config>,
+) -> (
+    SyncSender<(Connection, ConnectionSlot)>,
+    Vec<JoinHandle<()>>,
+) {
+    let workers = workers.get();
+    // One waiting connection per worker. Anything more than that gets a 503.
+    let (sender, receiver) = mpsc::sync_channel::<(Connection, ConnectionSlot)>(workers);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    let mut handles = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let receiver = Arc::clone(&receiver);
+        let config = Arc::clone(config);
+        handles.push(thread::spawn(move || {
+            loop {
+                let next = receiver
+                    .lock()
+                    .expect("Worker queue lock is poisoned")
+                    .recv();
+                // The slot is dropped, and so freed, at the end of each iteration
+                let Ok((stream, _slot)) = next else {
+                    // The sender is gone, so the server is shutting down
+                    break;
+                };
+                // Don't let one bad request take a worker down with it
+                if panic::catch_unwind(AssertUnwindSafe(|| serve_connection(stream, &config)))
+                    .is_err()
+                {
+                    error!("Worker panicked while handling a request.");
+                }
+            }
+        }));
+    }
+
+    (sender, handles)
+}
+
+// Decides whether to serve a new connection, and answers it if not
+fn admit(
+    stream: &mut Connection,
+    peer: IpAddr,
+    access: &AccessList,
+    connections: &ConnectionLimit,
+    config: &Config,
+    overload: &mut OverloadWarning,
+) -> Option<ConnectionSlot> {
+    // We can't answer over TLS without doing the handshake here, so rejected TLS connections just get closed
+    if stream.is_tcp() && !access.permits(peer) {
+        debug!(
+            "Rejecting connection from {} since it is not allowed.",
+            log_ip(peer)
+        );
+        if config.tls.is_none() {
+            error_stream(stream, 403, false);
+            discard_request(stream);
+        }
+        return None;
+    }
+    // Rate limiting. Clients behind a trusted proxy are rate-limited once their request says who they are.
+    if stream.is_tcp()
+        && !config.trusts(peer)
+        && let Some(left) = config.ratelimited_connection(peer)
+    {
+        if config.tls.is_none() {
+            let json = wants_json(stream);
+            retry_later_stream(stream, 429, left, json, false);
+            discard_request(stream);
+        }
+        return None;
+    }
+    // A trusted proxy's connections carry many clients, so they don't count as one
+    let client = (stream.is_tcp() && !config.trusts(peer)).then_some(peer);
+    match connections.acquire(client) {
+        Ok(slot) => return Some(slot),
+        Err(Rejection::Full) => {
+            debug!(
+                "Too many open connections. Rejecting connection from {}.",
+                log_ip(peer)
+            );
+            overload.reject(config, "too many open connections");
+        }
+        Err(Rejection::ClientFull) => {
+            debug!(
+                "Too many open connections from {}. Rejecting connection.",
+                log_ip(peer)
+            );
+        }
+    }
+    if config.tls.is_none() {
+        let json = wants_json(stream);
+        retry_later_stream(stream, 503, CONNECTION_LIMIT_RETRY_AFTER, json, false);
+        discard_request(stream);
+    }
+    None
+}
+
+// Hands the stream to a worker, or rejects it if they are all busy
+fn dispatch(
+    pool: &SyncSender<(Connection, ConnectionSlot)>,
+    stream: Connection,
+    slot: ConnectionSlot,
+    config: &Config,
+    overload: &mut OverloadWarning,
+) {
+    match pool.try_send((stream, slot)) {
+        Ok(()) => {}
+        Err(TrySendError::Full((mut stream, _))) => {
+            debug!("All workers are busy. Rejecting connection.");
+            overload.reject(config, "all workers are busy");
+            // Same as with rate limiting, we can't answer over TLS from here
+            if config.tls.is_none() {
+                error_stream(&mut stream, 503, false);
+                discard_request(&mut stream);
+            }
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            error!("All workers have stopped. Cannot handle connections.");
+        }
+    }
+}
+
+// Keeps us from spinning when accepting fails repeatedly
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// An accepted connection, over TCP or a Unix socket
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    // Unix socket clients are on this machine, but don't have an address
+    fn peer_ip(&self) -> io::Result<IpAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().map(|addr| addr.ip()),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(Ipv4Addr::LOCALHOST.into()),
+        }
+    }
+
+    const fn is_tcp(&self) -> bool {
+        matches!(self, Self::Tcp(_))
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    // Nagle's algorithm is a TCP thing, so there's nothing to turn off on a Unix socket
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(()),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    // Peeking at a Unix socket isn't stable yet, so it just looks like nothing has arrived
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.peek(buf),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(0),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Where a listener listens, for the logs and for waking it up at shutdown
+#[derive(Clone)]
+enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Listener {
+    fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Self::Unix(_, path) => Ok(ListenAddr::Unix(path.clone())),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Self::Tcp(listener) => listener.accept().map(|(stream, _)| Connection::Tcp(stream)),
+            #[cfg(unix)]
+            Self::Unix(listener, _) => listener
+                .accept()
+                .map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+
+    // std picks a small backlog, which a burst of connections fills before the accept loop gets to them.
+    // Calling listen again on a listening socket just changes its backlog, so there's no need to build the socket ourselves.
+    #[cfg(unix)]
+    fn set_backlog(&self, backlog: i32) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let fd = match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix(listener, _) => listener.as_raw_fd(),
+        };
+        // SAFETY: `listen` only takes a descriptor and an integer, and the descriptor is a socket we own and keep open
+        // for as long as this borrow lasts. Nothing else is passed in or handed back.
+        if unsafe { libc::listen(fd, backlog) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Not worth unsafe Windows API calls for a tuning knob, so it stays at std's default there
+    #[cfg(not(unix))]
+    #[expect(
+        clippy::unnecessary_wraps,
+        reason = "Same signature as the Unix version"
+    )]
+    fn set_backlog(&self, _backlog: i32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ListenAddr {
+    fn url(&self, scheme: &str) -> String {
+        match self {
+            Self::Tcp(addr) => format!("{scheme}://{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => format!("{scheme}+unix://{}", path.display()),
+        }
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        match self {
+            Self::Tcp(addr) => {
+                let mut addr = *addr;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(if addr.is_ipv4() {
+                        Ipv4Addr::LOCALHOST.into()
+                    } else {
+                        Ipv6Addr::LOCALHOST.into()
+                    });
+                }
+                TcpStream::connect(addr).map(drop)
+            }
+            #[cfg(unix)]
+            Self::Unix(path) => UnixStream::connect(path).map(drop),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+fn bind_listeners(listen: &Listen) -> io::Result<Vec<Listener>> {
+    let listeners = match &listen.unix_socket {
+        Some(path) => vec![bind_unix_socket(path)?],
+        None => listen
+            .addrs
+            .iter()
+            .map(|addr| bind_listener(addr))
+            .collect::<io::Result<_>>()?,
+    };
+    for listener in &listeners {
+        listener.set_backlog(listen.backlog).map_err(|e| {
+            io::Error::new(e.kind(), format!("Could not set the listen backlog: {e}"))
+        })?;
+    }
+    Ok(listeners)
+}
+
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> io::Result<Listener> {
+    // A socket left behind by a server that didn't get to clean up would make binding fail
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+        && UnixStream::connect(path).is_err()
+    {
+        fs::remove_file(path).unwrap_or_default();
+    }
+    UnixListener::bind(path)
+        .map(|listener| Listener::Unix(listener, path.to_path_buf()))
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to bind to {}: {e}", path.display()),
+            )
+        })
+}
+
+#[cfg(not(unix))]
+fn bind_unix_socket(_path: &Path) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform.",
+    ))
+}
+
+const DEFAULT_BACKLOG: i32 = 1024;
+
+// Not being able to listen is almost always a typo or a permissions problem, so explain instead of printing the raw error
+fn bind_listener(addr: &str) -> io::Result<Listener> {
+    match TcpListener::bind(addr) {
+        Ok(listener) => Ok(Listener::Tcp(listener)),
+        Err(e) => {
+            let detail = e.to_string();
+            let reason = match e.kind() {
+                io::ErrorKind::AddrInUse => {
+                    "The address is already in use. Is another server running on that port?"
+                }
+                io::ErrorKind::PermissionDenied => {
+                    "Permission denied. Ports below 1024 usually need root."
+                }
+                io::ErrorKind::AddrNotAvailable => "The address does not belong to this machine.",
+                _ => &detail,
+            };
+            Err(io::Error::new(
+                e.kind(),
+                format!("Failed to bind to {addr}: {reason}"),
+            ))
+        }
+    }
+}
+
+// Every listener gets its own accept loop, all feeding the same channel
+fn accept_connections(
+    listeners: Vec<Listener>,
+    shutdown: &Arc<AtomicBool>,
+    nodelay: bool,
+) -> Receiver<Connection> {
+    let (sender, receiver) = mpsc::channel();
+    for listener in listeners {
+        let sender = sender.clone();
+        let shutdown = Arc::clone(shutdown);
+        thread::spawn(move || {
+            loop {
+                let stream = listener.accept();
+                if shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        if nodelay && stream.set_nodelay(true).is_err() {
+                            debug!("Could not disable Nagle's algorithm on a connection.");
+                        }
+                        if sender.send(stream).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        // Usually temporary, like running out of file descriptors
+                        warn!("Could not accept connection: {err}");
+                        thread::sleep(ACCEPT_ERROR_BACKOFF);
+                    }
+                }
+            }
+        });
+    }
+    receiver
+}
+
+// Socket files outlive the listener, and would be in the next server's way
+#[cfg(unix)]
+fn remove_unix_sockets(listen_addrs: &[ListenAddr]) {
+    for addr in listen_addrs {
+        if let ListenAddr::Unix(path) = addr {
+            fs::remove_file(path).unwrap_or_default();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+const fn remove_unix_sockets(_listen_addrs: &[ListenAddr]) {}
+
+// How long in-flight requests get to finish after a shutdown signal
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// Sets `shutdown` on SIGINT/SIGTERM. A second signal exits immediately.
+fn register_signals(shutdown: &Arc<AtomicBool>) -> io::Result<()> {
+    for signal in [SIGINT, SIGTERM] {
+        // Registered first so it only fires if we are already shutting down
+        flag::register_conditional_shutdown(signal, 1, Arc::clone(shutdown))?;
+        flag::register(signal, Arc::clone(shutdown))?;
+    }
+    Ok(())
+}
+
+// Wakes up the accept loops once `shutdown` is set, however that happened.
+// Whoever sets it can unpark the returned thread to skip the wait.
+fn wake_on_shutdown(listen_addrs: Vec<ListenAddr>, flag: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while !flag.load(Ordering::Relaxed) {
+            thread::park_timeout(Duration::from_millis(100));
+        }
+        info!("Shutting down...");
+        // The accept loops are blocked until a connection comes in, so we make one for each
+        for wake_addr in listen_addrs {
+            if wake_addr.wake().is_err() {
+                error!(
+                    "Could not wake up the listener on {wake_addr}. It will stop after the next connection."
+                );
+            }
+        }
+    })
+}
+
+fn wait_for_workers(workers: &[JoinHandle<()>]) {
+    let deadline = Instant::now() + SHUTDOWN_GRACE_PERIOD;
+    while !workers.iter().all(JoinHandle::is_finished) {
+        if Instant::now() > deadline {
+            warn!("Some requests did not finish in time. Stopping anyway.");
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Keys are option names, like `keep-alive-timeout` or `address`, and values are what would be passed on the command line.
+// Options are added to the command line as if they had been given there, so `requires` and `conflicts_with` apply to them too.
+// Anything given on the command line or in the environment still wins.
+#[must_use]
+pub fn parse_cli() -> Cli {
+    let mut command = Cli::command();
+    let matches = command.get_matches_mut();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let Some(path) = &cli.config else {
+        return cli;
+    };
+    let settings = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| {
+            toml::from_str::<HashMap<String, toml::Value>>(&text).map_err(|e| e.to_string())
+        });
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(e) => command
+            .error(
+                ErrorKind::Io,
+                format!("Could not read config file {}: {e}", path.display()),
+            )
+            .exit(),
+    };
+
+    let mut from_file: Vec<std::ffi::OsString> = Vec::new();
+    for (key, value) in settings {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == key.as_str() || arg.get_long() == Some(key.as_str()))
+            .filter(|arg| arg.get_id() != "config");
+        let Some(arg) = arg else {
+            command
+                .error(
+                    ErrorKind::UnknownArgument,
+                    format!("Unknown option `{key}` in config file"),
+                )
+                .exit();
+        };
+        let values = match value {
+            toml::Value::Array(values) => values.iter().map(config_value).collect(),
+            value => config_value(&value).map(|value| vec![value]),
+        };
+        let Some(values) = values else {
+            command
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("Option `{key}` in config file should be a string, number, boolean or array of them"),
+                )
+                .exit();
+        };
+        if matches!(
+            matches.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        let id = arg.get_id().clone();
+        let Some(long) = arg.get_long() else {
+            // Positional, like the address and port, which can't conflict with anything
+            command = command.mut_arg(id, |arg| arg.default_values(values));
+            continue;
+        };
+        let flag = !arg.get_action().takes_values();
+        for value in values {
+            match value.as_str() {
+                "true" if flag => from_file.push(format!("--{long}").into()),
+                "false" if flag => {}
+                _ => from_file.push(format!("--{long}={value}").into()),
+            }
+        }
+    }
+
+    // Right after the program name, so a `--` on the real command line can't turn them into positionals
+    let mut args = std::env::args_os();
+    let matches = command.get_matches_from(args.next().into_iter().chain(from_file).chain(args));
+    Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
+
+fn config_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn setup_config(cli: &Cli) -> io::Result<Config> {
+    let mut normalizedblist: Vec<PathBuf> = Vec::new();
+
+    let root = setup_root(&cli.root, cli.strict_permissions)?;
+
+    setup_blacklist(
+        cli.blacklist.clone(),
+        &root,
+        [&cli.logfile, &cli.full_logfile],
+        &mut normalizedblist,
+    )?;
+    let access_log = (cli.access_log_format == AccessLogFormat::Clf)
+        .then(|| setup_access_log(&cli.access_log, &mut normalizedblist).map(Arc::new))
+        .transpose()?;
+    info!("Blacklist: {:?}", normalizedblist);
+    if cli.enablelogfiles && normalizedblist.is_empty() {
+        warn!("Blacklist is empty, log files could be exposed.");
+    }
+
+    #[cfg(on_nightly)]
+    let syms = cli.allow_external_symlinks;
+    #[cfg(not(on_nightly))]
+    let syms = false;
+
+    let tls = cli
+        .cert
+        .as_deref()
+        .zip(cli.key.as_deref())
+        .map(|(cert, key)| setup_tls(cert, key))
+        .transpose()?;
+
+    let mut config = Config {
+        root,
+        file: cli.file.as_deref().map(setup_file).transpose()?,
+        blacklist: normalizedblist,
+        allow_symlinks: syms,
+        follow_symlinks: !cli.no_follow_symlinks,
+        dot_files: if cli.serve_hidden_files {
+            DotFiles::Serve
+        } else {
+            DotFiles::Hide
+        },
+        tls,
+        gzip_types: cli.gzip_types.clone(),
+        gzip_min_size: cli.gzip_min_size,
+        max_rate: cli.max_rate,
+        charset: (!cli.default_charset.is_empty()).then(|| cli.default_charset.clone()),
+        cache_max_age: cli.cache_max_age,
+        immutable_pattern: cli.immutable_pattern.clone(),
+        dir_listing: if cli.no_dir_listing {
+            DirListing::Disabled
+        } else if cli.listing_details {
+            DirListing::Details
+        } else {
+            DirListing::Names
+        },
+        listing_template: cli
+            .listing_template
+            .as_deref()
+            .map(setup_listing_template)
+            .transpose()?,
+        listing_format: if cli.list_json {
+            ListingFormat::Json
+        } else {
+            ListingFormat::Negotiated
+        },
+        index: cli.index.clone(),
+        auth: cli.auth.clone(),
+        keep_alive_timeout: Duration::from_secs(cli.keep_alive_timeout),
+        max_keep_alive_requests: cli.max_keep_alive_requests,
+        access_log,
+        header_timeout: Duration::from_secs(cli.header_timeout),
+        max_header_bytes: cli.max_header_bytes,
+        max_uri_length: cli.max_uri_length,
+        health_path: (!cli.health_path.is_empty()).then(|| cli.health_path.clone()),
+        metrics: Arc::default(),
+        metrics_path: cli.metrics_path.clone(),
+        admin_token: cli.admin_token.clone(),
+        shutdown: Arc::default(),
+        ratelimiter: (cli.ratelimit > 0).then(|| Arc::new(Mutex::new(RateLimiter::new(cli)))),
+        trusted_proxies: cli.trusted_proxy.clone(),
+        vhosts: Vec::new(),
+        strict_vhosts: cli.strict_vhosts,
+        redirects: setup_redirects(cli),
+        cors: (!cli.cors_origin.is_empty()).then(|| Cors {
+            origins: cli.cors_origin.clone(),
+            methods: cli.cors_methods.join(", "),
+        }),
+        listen: setup_listen(cli),
+        output: Output::from_cli(cli),
+    };
+    if config.auth.is_some() && config.tls.is_none() {
+        warn!(
+            "Basic authentication sends credentials in plain text. Consider using --cert and --key."
+        );
+    }
+    if config.admin_token.is_some() && config.tls.is_none() {
+        warn!("The admin token is sent in plain text. Consider using --cert and --key.");
+    }
+    config.vhosts = cli
+        .vhost
+        .iter()
+        .map(|vhost| Ok((vhost.host.clone(), setup_vhost(vhost, &config, cli)?)))
+        .collect::<io::Result<_>>()?;
+
+    Ok(config)
+}
+
+fn setup_listen(cli: &Cli) -> Listen {
+    Listen {
+        addrs: if cli.bind.is_empty() {
+            vec![format!("{}:{}", cli.address, cli.port)]
+        } else {
+            cli.bind.iter().map(ToString::to_string).collect()
+        },
+        unix_socket: cli.unix_socket.clone(),
+        backlog: cli.backlog,
+        tcp_nodelay: cli.tcp_nodelay,
+        access: AccessList {
+            allow: cli.allow.clone(),
+            deny: cli.deny.clone(),
+        },
+        max_connections: cli.max_connections,
+        max_connections_per_ip: cli.max_connections_per_ip,
+        workers: (!cli.singlethreaded).then_some(cli.workers),
+    }
+}
+
+// Request paths to their redirect status and target
+fn setup_redirects(cli: &Cli) -> HashMap<String, (u16, String)> {
+    cli.redirect
+        .iter()
+        .map(|redirect| (301, redirect))
+        .chain(
+            cli.temporary_redirect
+                .iter()
+                .map(|redirect| (302, redirect)),
+        )
+        .map(|(status, redirect)| (redirect.from.clone(), (status, redirect.to.clone())))
+        .collect()
+}
+
+// Everything but the root is shared with the default site. The blacklist is relative to each root.
+fn setup_vhost(vhost: &VirtualHost, config: &Config, cli: &Cli) -> io::Result<Config> {
+    info!("Virtual host: {}", vhost.host);
+    let root = setup_root(&vhost.root, cli.strict_permissions)?;
+    let mut blacklist = config.blacklist.clone();
+    if let Some(entries) = &cli.blacklist {
+        blacklist.extend(
+            entries
+                .iter()
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| root.join(entry)),
+        );
+    }
+    Ok(Config {
+        root,
+        blacklist,
+        ..config.clone()
+    })
+}
+
+// Tests wait for the "Serving on:" lines to find the port, so keep them as they are
+fn announce(listen_addrs: &[ListenAddr], config: &Config, check: bool) {
+    for addr in listen_addrs {
+        info!(
+            "{} {}",
+            if check {
+                "Would serve on:"
+            } else {
+                "Serving on:"
+            },
+            addr.url(if config.tls.is_some() {
+                "https"
+            } else {
+                "http"
+            })
+        );
+    }
+}
+
+/// Runs the server like the binary does, until it gets SIGINT or SIGTERM.
+///
+/// Anything wrong with the configuration is logged and exits the process.
+/// [`Config::from_cli`] and [`serve`] do the same, but return errors instead.
+pub fn run(cli: &Cli) {
+    // We need to do this ASAP
+    if cli.testing {
+        let oldhook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            oldhook(info);
+            exit(1);
+        }));
+    }
+
+    setup_logger(cli);
+
+    let served = Config::from_cli(cli).and_then(|config| {
+        if cli.check {
+            check(&config)
+        } else {
+            serve(config)
+        }
+    });
+    if let Err(e) = served {
+        error!("{e}");
+        exit(1);
+    }
+}
+
+// Everything that can't work has failed by the time this gets called, so all that's left is to bind and let go again
+fn check(config: &Config) -> io::Result<()> {
+    let listeners = bind_listeners(&config.listen)?;
+    let listen_addrs = listeners
+        .iter()
+        .map(Listener::local_addr)
+        .collect::<io::Result<Vec<_>>>()?;
+    announce(&listen_addrs, config, true);
+    for path in config.blacklist.iter().filter(|path| !path.exists()) {
+        warn!("Blacklisted file {} does not exist.", path.display());
+    }
+    remove_unix_sockets(&listen_addrs);
+    info!("Configuration OK.");
+    Ok(())
+}
+
+/// Serves `config` until the process gets SIGINT or SIGTERM, or the admin endpoint is asked to shut down.
+///
+/// Settings that change how responses and log lines look, like `--quiet-errors`, apply to the whole process.
+///
+/// # Errors
+///
+/// If a listen address can't be bound or read back, or the signal handlers can't be registered.
+pub fn serve(config: Config) -> io::Result<()> {
+    register_signals(&config.shutdown)?;
+    Server::start(config)?.wait();
+    Ok(())
+}
+
+/// A server accepting connections on its own thread, from [`Server::start`].
+pub struct Server {
+    listen_addrs: Vec<ListenAddr>,
+    shutdown: Arc<AtomicBool>,
+    waker: JoinHandle<()>,
+    accepting: JoinHandle<()>,
+}
+
+impl Server {
+    /// Binds every listen address and starts serving `config` in the background.
+    ///
+    /// The listeners are bound by the time this returns, so connections made from then on get queued up.
+    /// Settings that change how responses and log lines look, like `--quiet-errors`, apply to the whole process.
+    /// Nothing handles signals, so stop it with [`Server::stop`].
+    ///
+    /// # Errors
+    ///
+    /// If a listen address can't be bound or read back.
+    pub fn start(config: Config) -> io::Result<Self> {
+        let listeners = bind_listeners(&config.listen)?;
+        let listen_addrs = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<io::Result<Vec<_>>>()?;
+        config.output.apply();
+        let shutdown = Arc::clone(&config.shutdown);
+        let waker = wake_on_shutdown(listen_addrs.clone(), Arc::clone(&shutdown));
+        announce(&listen_addrs, &config, false);
+
+        let addrs = listen_addrs.clone();
+        let accepting = thread::spawn(move || accept_loop(listeners, &addrs, config));
+        Ok(Self {
+            listen_addrs,
+            shutdown,
+            waker,
+            accepting,
+        })
+    }
+
+    /// The first TCP address the server listens on, with the port the OS picked if it was 0.
+    /// None if it only listens on a Unix socket.
+    #[must_use]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listen_addrs.iter().find_map(|addr| match addr {
+            ListenAddr::Tcp(addr) => Some(*addr),
+            #[cfg(unix)]
+            ListenAddr::Unix(_) => None,
+        })
+    }
+
+    /// Stops accepting connections and waits for the ones in progress, like SIGTERM would.
+    pub fn stop(self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.waker.thread().unpark();
+        self.wait();
+    }
+
+    /// Waits until the server stops, which only the admin endpoint can make it do on its own.
+    pub fn wait(self) {
+        if let Err(panic) = self.accepting.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
+
+fn accept_loop(listeners: Vec<Listener>, listen_addrs: &[ListenAddr], config: Config) {
+    let config = Arc::new(config);
+    let connections = ConnectionLimit::new(
+        config.listen.max_connections,
+        config.listen.max_connections_per_ip,
+    );
+    let mut overload = OverloadWarning::new();
+
+    let pool = config
+        .listen
+        .workers
+        .map(|workers| spawn_workers(workers, &config));
+
+    let incoming = accept_connections(listeners, &config.shutdown, config.listen.tcp_nodelay);
+    loop {
+        let mut stream = match incoming.recv_timeout(ACCEPT_POLL_INTERVAL) {
+            Ok(stream) => stream,
+            Err(RecvTimeoutError::Timeout) => {
+                overload.flush();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if config.shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let Ok(peer) = stream.peer_ip() else {
+            error!("Could not get peer IP address.");
+            continue;
+        };
+        let Some(slot) = admit(
+            &mut stream,
+            peer,
+            &config.listen.access,
+            &connections,
+            &config,
+            &mut overload,
+        ) else {
+            continue;
+        };
+        // Handler
+
+        if let Some((pool, _)) = &pool {
+            // Multithreaded mode:
+            dispatch(pool, stream, slot, &config, &mut overload);
+        } else {
+            // Single threaded mode:
+            serve_connection(stream, &config);
+        }
+    }
+
+    // Whatever was rejected since the last warning would go unmentioned otherwise
+    overload.warn();
+    if let Some((pool, workers)) = pool {
+        // Lets the workers finish what is already queued and then stop
+        drop(pool);
+        wait_for_workers(&workers);
+    }
+    remove_unix_sockets(listen_addrs);
+    info!("Server stopped.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory per test, since tests run in parallel
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "simplewebserver-unit-{name}-{}",
+            std::process::id()
+        ));
+        if root.exists() {
+            fs::remove_dir_all(&root).expect("Could not clear the old test root");
+        }
+        fs::create_dir_all(&root).expect("Could not create the test root");
+        root.canonicalize()
+            .expect("Could not canonicalize the test root")
+    }
+
+    #[test]
+    fn root_maps_to_the_root_directory() {
+        let root = temp_root("root");
+
+        let (canonical, _) = server_path_to_local_path("/", &root).expect("The root exists");
+
+        assert_eq!(canonical, root);
+    }
+
+    #[test]
+    fn missing_extension_falls_back_to_html() {
+        let root = temp_root("html_fallback");
+        fs::write(root.join("about.html"), "About").expect("Could not write about.html");
+
+        let (canonical, requested) =
+            server_path_to_local_path("/about", &root).expect("about.html exists");
+
+        assert_eq!(canonical, root.join("about.html"));
+        assert_eq!(requested, root.join("about.html"));
+    }
+
+    #[test]
+    fn existing_files_are_used_as_they_are() {
+        let root = temp_root("existing");
+        fs::create_dir(root.join("css")).expect("Could not create css");
+        fs::write(root.join("css").join("style.css"), "").expect("Could not write style.css");
+        // Without an extension, but there, so it doesn't get one
+        fs::write(root.join("LICENSE"), "").expect("Could not write LICENSE");
+        fs::write(root.join("LICENSE.html"), "").expect("Could not write LICENSE.html");
+
+        let (style, _) =
+            server_path_to_local_path("/css/style.css", &root).expect("style.css exists");
+        let (license, _) = server_path_to_local_path("/LICENSE", &root).expect("LICENSE exists");
+
+        assert_eq!(style, root.join("css").join("style.css"));
+        assert_eq!(license, root.join("LICENSE"));
+    }
+
+    #[test]
+    fn bad_configurations_are_errors() {
+        let root = temp_root("bad_configuration");
+        fs::write(root.join("listing.html"), "No placeholder")
+            .expect("Could not write listing.html");
+        let config = |args: &[&Path]| {
+            let args = args.iter().map(|arg| arg.as_os_str());
+            let cli = Cli::parse_from(std::iter::once("simplewebserver_rs".as_ref()).chain(args));
+            Config::from_cli(&cli).map(drop).map_err(|e| e.kind())
+        };
+
+        assert_eq!(config(&["--root".as_ref(), &root]), Ok(()));
+        assert_eq!(
+            config(&["--root".as_ref(), &root.join("missing")]),
+            Err(io::ErrorKind::NotFound)
+        );
+        assert_eq!(
+            config(&[
+                "--root".as_ref(),
+                &root,
+                "--listing-template".as_ref(),
+                &root.join("listing.html")
+            ]),
+            Err(io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[test]
+    fn secs_left_rounds_up() {
+        assert_eq!(secs_left(Duration::ZERO), 0);
+        assert_eq!(secs_left(Duration::from_millis(1)), 1);
+        assert_eq!(secs_left(Duration::from_secs(2)), 2);
+        assert_eq!(secs_left(Duration::from_millis(2001)), 3);
+    }
+
+    #[test]
+    fn built_in_listing_renders_without_entries() {
+        let page = render_listing(None, "/empty/", "");
+
+        assert!(page.contains("<title>Directory listing of /empty/</title>"));
+        assert!(page.contains("<ul>\n    \n</ul>"));
+        assert!(!page.contains("{lis}"));
+    }
+
+    #[test]
+    #[expect(
+        clippy::literal_string_with_formatting_args,
+        reason = "These are the template's placeholders, not format arguments"
+    )]
+    fn listing_leaves_placeholders_in_names_alone() {
+        let lis = "<li>{directory} {lis}.txt</li>";
+
+        let built_in = render_listing(None, "/", lis);
+        let custom = render_listing(Some("<h1>{directory}</h1>{lis}"), "/", lis);
+
+        assert!(built_in.contains(lis));
+        assert_eq!(custom, format!("<h1>/</h1>{lis}"));
+    }
+
+    #[test]
+    fn named_encodings_outrank_the_wildcard() {
+        assert!(accepts_encoding("gzip, br", "gzip"));
+        assert!(accepts_encoding("*", "gzip"));
+        assert!(accepts_encoding("X-GZIP", "gzip"));
+        assert!(!accepts_encoding("br", "gzip"));
+        assert!(!accepts_encoding("*;q=0", "gzip"));
+        assert!(!accepts_encoding("gzip;q=0, *", "gzip"));
+        assert!(!accepts_encoding("*, gzip;q=0", "gzip"));
+        assert!(accepts_encoding("gzip;q=0, *", "br"));
+        assert!(accepts_encoding("*;q=0, gzip", "gzip"));
+    }
+
+    #[test]
+    fn proxy_style_targets_are_unsupported() {
+        assert_eq!(unsupported_target_form("/"), None);
+        assert_eq!(unsupported_target_form("/http://evil/"), None);
+        assert_eq!(unsupported_target_form("*"), None);
+        assert_eq!(unsupported_target_form("garbage"), None);
+        assert_eq!(
+            unsupported_target_form("http://evil/"),
+            Some("absolute-form")
+        );
+        assert_eq!(
+            unsupported_target_form("HTTPS://evil:443/page.html?x=1"),
+            Some("absolute-form")
+        );
+        assert_eq!(unsupported_target_form("evil:443"), Some("authority-form"));
+        assert_eq!(
+            unsupported_target_form("[::1]:8080"),
+            Some("authority-form")
+        );
+    }
+
+    #[test]
+    fn missing_files_map_to_nothing() {
+        let root = temp_root("missing");
+
+        fs::write(root.join("file.txt"), "").expect("Could not write file.txt");
+        let kind = |path| {
+            server_path_to_local_path(path, &root)
+                .expect_err("The file is missing")
+                .kind()
+        };
+
+        assert_eq!(kind("/missing.txt"), io::ErrorKind::NotFound);
+        assert_eq!(kind("/missing"), io::ErrorKind::NotFound);
+        assert_eq!(kind("/missing/page.html"), io::ErrorKind::NotFound);
+        assert_eq!(kind("/file.txt/page.html"), io::ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn impossible_names_are_invalid() {
+        let root = temp_root("impossible");
+        let kind = |path: &str| {
+            server_path_to_local_path(path, &root)
+                .expect_err("No file can have this name")
+                .kind()
+        };
+
+        assert_eq!(
+            kind(&format!("/{}", "a".repeat(8000))),
+            io::ErrorKind::InvalidFilename
+        );
+        assert_eq!(kind("/nul\0.txt"), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn broken_lookups_are_not_missing_files() {
+        let root = temp_root("broken");
+        std::os::unix::fs::symlink("loop.txt", root.join("loop.txt"))
+            .expect("Could not create loop.txt");
+
+        let kind = server_path_to_local_path("/loop.txt", &root)
+            .expect_err("The symlink loops")
+            .kind();
+
+        assert_ne!(kind, io::ErrorKind::NotFound);
+        assert_ne!(kind, io::ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn dot_dot_is_only_resolved_in_the_canonical_path() {
+        let root = temp_root("dot_dot");
+        fs::create_dir(root.join("inner")).expect("Could not create inner");
+        fs::write(root.join("page.html"), "").expect("Could not write page.html");
+
+        let (canonical, requested) =
+            server_path_to_local_path("/inner/../page.html", &root).expect("page.html exists");
+        let (outside, _) = server_path_to_local_path("/..", &root).expect("The parent exists");
+
+        assert_eq!(canonical, root.join("page.html"));
+        assert_eq!(requested, root.join("inner/../page.html"));
+        // Keeping requests inside the root is up to the caller
+        assert!(!outside.starts_with(&root));
+    }
+}
@@ -1,16 +1,29 @@
 // tests/test_server.rs
+use clap::Parser;
 use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::net::{Shutdown, TcpStream};
 use std::panic;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, mpsc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 struct Server {
     child: Child,
     port: u16,
+    // Collects what the server logs to stdout, for tests that check it
+    output: Option<thread::JoinHandle<String>>,
+}
+
+impl Server {
+    /// Stops the server and returns everything it logged to stdout
+    fn stop(mut self) -> String {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+        self.output.take().unwrap().join().unwrap()
+    }
 }
 
 fn getserver(args: &[&str]) -> Server {
@@ -30,20 +43,68 @@ fn getserver(args: &[&str]) -> Server {
         path
     });
 
-    let port = port_check::free_local_ipv4_port().unwrap();
-
-    println!("Server port: {port}");
-
-    let child = Command::new(SERVER_BINARY.as_path())
+    // Port 0 lets the OS pick a free one, so parallel tests can't collide
+    let mut child = Command::new(SERVER_BINARY.as_path())
         .env_clear()
-        .args(["127.0.0.1", port.to_string().as_str()])
+        .args(["127.0.0.1", "0"])
         .args(args)
+        .stdout(Stdio::piped())
         .spawn()
         .unwrap();
 
-    thread::sleep(Duration::from_millis(100));
+    // The server says where it listens once it is bound, and connections from then on get queued up
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    let output = thread::spawn(move || {
+        let mut log = String::new();
+        for line in stdout.lines().map_while(Result::ok) {
+            println!("{line}");
+            if let Some(url) = line.split("Serving on: ").nth(1) {
+                // Unix sockets don't have a port
+                let port = url.rsplit(':').next().and_then(|port| port.parse().ok());
+                let _ = sender.send(port.unwrap_or(0));
+            }
+            log.push_str(&line);
+            log.push('\n');
+        }
+        log
+    });
+    let port = receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("Server did not start listening");
+
+    println!("Server port: {port}");
+
+    Server {
+        child,
+        port,
+        output: Some(output),
+    }
+}
+
+/// Runs the server on threads of the test process instead, which is quicker than starting the binary.
+/// Nothing gets logged, and settings like --quiet-errors are process-wide, so tests of those need `getserver`.
+fn start_server(args: &[&str]) -> InProcess {
+    let cli = simplewebserver_rs::Cli::parse_from(
+        ["simplewebserver_rs", "127.0.0.1", "0"].iter().chain(args),
+    );
+    let config = simplewebserver_rs::Config::from_cli(&cli).unwrap();
+    let server = simplewebserver_rs::Server::start(config).unwrap();
+    InProcess {
+        port: server.local_addr().unwrap().port(),
+        server,
+    }
+}
+
+struct InProcess {
+    server: simplewebserver_rs::Server,
+    port: u16,
+}
 
-    Server { child, port }
+impl InProcess {
+    fn stop(self) {
+        self.server.stop();
+    }
 }
 
 /// This is fine to call multiple times
@@ -56,18 +117,44 @@ fn set_panic_hook() {
     }))
 }
 
-fn get_path(path: &str, port: u16) -> TcpStream {
+/// Creates an empty directory to use as a web root
+fn temp_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!(
+        "simplewebserver-test-{name}-{}",
+        std::process::id()
+    ));
+    if root.exists() {
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+    std::fs::create_dir_all(&root).unwrap();
+    root
+}
+
+fn request(method: &str, path: &str, port: u16) -> TcpStream {
+    request_with_headers(method, path, &[], port)
+}
+
+fn request_with_headers(method: &str, path: &str, headers: &[&str], port: u16) -> TcpStream {
     let mut conn = TcpStream::connect(("127.0.0.1", port)).unwrap();
-    conn.write_all(format!("GET {path} HTTP/1.0\n\n").as_bytes())
-        .unwrap();
+    let mut message = format!("{method} {path} HTTP/1.0\n");
+    for header in headers {
+        message.push_str(header);
+        message.push('\n');
+    }
+    message.push('\n');
+    conn.write_all(message.as_bytes()).unwrap();
     conn.flush().unwrap();
     conn
 }
 
+fn get_path(path: &str, port: u16) -> TcpStream {
+    request("GET", path, port)
+}
+
 #[test]
 /// Test that concurrency features are working
 pub fn test_concurrent() {
-    let mut server = getserver(&[]);
+    let server = start_server(&[]);
 
     set_panic_hook();
 
@@ -93,7 +180,7 @@ pub fn test_concurrent() {
 
     thread::sleep(Duration::from_millis(10));
 
-    server.child.kill().unwrap();
+    server.stop();
 
     if !handle.is_finished() {
         panic!("Concurrency is not working!");
@@ -101,125 +188,3032 @@ pub fn test_concurrent() {
     println!("Concurrency is working!");
 }
 
+#[test]
+pub fn test_worker_limit() {
+    let server = start_server(&["--workers", "1"]);
+
+    // Occupies the only worker
+    let busy = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    // Waits in the queue
+    let queued = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    let mut rejected = get_path("/", server.port);
+    rejected
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut response = String::new();
+    let _ = rejected.read_to_string(&mut response);
+
+    // Stopping waits for the worker to finish
+    drop((busy, queued));
+    server.stop();
+
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "Connection was not rejected:\n{response}"
+    );
+}
+
 #[test]
 pub fn test_404() {
-    let mut server = getserver(&[]);
-    let mut buf: [u8; 27] = [0; 27];
+    let server = start_server(&[]);
 
-    let _response = get_path("/invalid", server.port).read(&mut buf);
+    let mut response = String::new();
+    let _ = get_path("/invalid", server.port).read_to_string(&mut response);
 
-    server.child.kill().unwrap();
+    server.stop();
+
+    assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(response.contains("Content-Length: 4\r\n"));
+    assert!(response.ends_with("\r\n\r\n404\n"));
+}
+
+#[test]
+pub fn test_crlf_line_endings() {
+    let server = start_server(&[]);
+
+    let mut response = Vec::new();
+    let _ = get_path("/src/dirlist.html", server.port).read_to_end(&mut response);
 
+    server.stop();
+
+    let head_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("Response head is not terminated by CRLF CRLF");
+    let head = &response[..head_end];
+    // Every line ending in the head has to be a CRLF
     assert_eq!(
-        String::from_utf8_lossy(&buf),
-        "HTTP/1.1 404 Not Found\n\n404"
+        head.iter().filter(|&&b| b == b'\n').count(),
+        head.windows(2).filter(|w| w == b"\r\n").count()
     );
 }
 
 #[test]
-pub fn test_ratelimiting_1() {
-    let mut server = getserver(&["-r", "3", "-d", "2", "-v"]);
+pub fn test_date_header() {
+    let server = start_server(&[]);
 
-    for _ in 1..=2 {
-        let mut conn = get_path("/", server.port);
-        let mut buf: [u8; 9] = [0; 9];
-        let _ = conn.read(&mut buf).unwrap();
-        assert_eq!(Vec::from(buf), b"HTTP/1.1 ");
+    let mut found = String::new();
+    get_path("/Cargo.toml", server.port)
+        .read_to_string(&mut found)
+        .unwrap();
+    let mut missing = String::new();
+    get_path("/missing", server.port)
+        .read_to_string(&mut missing)
+        .unwrap();
+
+    server.stop();
+
+    for response in [found, missing] {
+        let date = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Date: "))
+            .unwrap();
+        // Like "Sun, 06 Nov 1994 08:49:37 GMT"
+        assert_eq!(date.len(), 29);
+        assert!(date.ends_with(" GMT"));
     }
+}
 
-    let mut ratelimited = get_path("/", server.port);
+#[test]
+pub fn test_server_header() {
+    let get = |args: &[&str]| {
+        let mut server = getserver(args);
+        let mut response = String::new();
+        get_path("/missing", server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        server.child.kill().unwrap();
+        response
+    };
 
-    let mut buf: [u8; 50] = [0; 50];
-    let _ = ratelimited.read(&mut buf).unwrap();
+    let with_header = get(&[]);
+    let without_header = get(&["--no-server-header"]);
 
-    server.child.kill().unwrap();
+    assert!(with_header.contains(&format!(
+        "Server: SimpleWebServer/{}\r\n",
+        env!("CARGO_PKG_VERSION")
+    )));
+    assert!(!without_header.contains("Server:"));
+}
 
-    assert_eq!(
-        Vec::from(buf),
-        b"HTTP/1.1 429 Too Many Requests\nRetry-After: 2\n\n429"
+#[test]
+pub fn test_content_type() {
+    let server = start_server(&[]);
+
+    let mut response = String::new();
+    let _ = get_path("/src/dirlist.html", server.port).read_to_string(&mut response);
+
+    server.stop();
+
+    assert!(
+        response.contains("Content-Type: text/html"),
+        "Wrong or missing Content-Type:\n{response}"
+    );
+    assert!(
+        response.contains(&format!(
+            "Content-Length: {}",
+            std::fs::metadata("src/dirlist.html").unwrap().len()
+        )),
+        "Wrong or missing Content-Length:\n{response}"
     );
 }
 
-// TEST OLD EXPLOITS
+#[test]
+pub fn test_head() {
+    let server = start_server(&[]);
+
+    let mut response = String::new();
+    let _ = request("HEAD", "/src/dirlist.html", server.port).read_to_string(&mut response);
+
+    let mut bad_method = String::new();
+    let _ = request("BREW", "/src/dirlist.html", server.port).read_to_string(&mut bad_method);
+
+    server.stop();
+
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(response.contains(&format!(
+        "Content-Length: {}",
+        std::fs::metadata("src/dirlist.html").unwrap().len()
+    )));
+    assert!(
+        response.ends_with("\r\n\r\n"),
+        "HEAD response has a body:\n{response}"
+    );
+
+    assert!(bad_method.starts_with("HTTP/1.0 405 Method Not Allowed\r\n"));
+    assert!(bad_method.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+}
 
 #[test]
-/// Ported from `exploit-0.0.1.sh`
-pub fn test_toctou_patched() {
-    const TOCTOU_TEST_LENGTH: u8 = 5;
+pub fn test_head_error() {
+    let server = start_server(&[]);
 
-    if Path::new("index.html").exists() {
-        println!("index.html already exists!");
-        println!("Consider this a skip.");
-        return;
-    }
+    // Any body on the 404 would be read as the start of the next response
+    let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    conn.write_all(
+        b"HEAD /missing HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .unwrap();
+    let mut responses = String::new();
+    conn.read_to_string(&mut responses).unwrap();
+    server.stop();
 
-    // We disable rate-limiting on the server
-    let mut server = getserver(&["--testing", "-r", "0"]);
+    let (missing, rest) = responses.split_once("\r\n\r\n").unwrap();
+    assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(missing.contains("\r\nContent-Length: 4\r\n"));
+    assert!(
+        rest.starts_with("HTTP/1.1 200 OK\r\n"),
+        "HEAD error response has a body:\n{responses}"
+    );
+    assert!(rest.ends_with(&std::fs::read_to_string("Cargo.toml").unwrap()));
+}
 
-    let start = time::OffsetDateTime::now_utc();
+#[test]
+pub fn test_web_root() {
+    let root = temp_root("web_root");
+    std::fs::write(root.join("index.html"), "Hello from the root").unwrap();
 
-    loop {
-        // Equivalent to `touch index.html`
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(false)
-            .open("index.html")
-            .unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
 
-        // Here, if following along in the shell script, we are interweaving the curl with the rm
-        let mut conn = get_path("/", server.port);
+    let mut index = String::new();
+    let _ = get_path("/", server.port).read_to_string(&mut index);
 
-        // This would be the `sleep 0.0015`
-        thread::sleep(Duration::from_micros(1000));
+    // Files from the current directory should not be reachable anymore
+    let mut outside = String::new();
+    let _ = get_path("/Cargo.toml", server.port).read_to_string(&mut outside);
 
-        std::fs::remove_file("index.html").unwrap();
+    server.stop();
+    std::fs::remove_dir_all(&root).unwrap();
 
-        // Wait for server to finish processing. (This would be the `wait $pid` line)
-        let _ = conn.read(&mut Vec::new()).unwrap();
+    assert!(index.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(index.ends_with("\r\n\r\nHello from the root"));
+    assert!(outside.starts_with("HTTP/1.0 404 Not Found\r\n"));
+}
 
-        // Break out of the loop if time has expired
-        if time::OffsetDateTime::now_utc() - start
-            > time::Duration::seconds(TOCTOU_TEST_LENGTH as i64)
-        {
-            break;
+#[test]
+pub fn test_index_files() {
+    let root = temp_root("index_files");
+    std::fs::create_dir_all(root.join("htm")).unwrap();
+    std::fs::create_dir_all(root.join("custom")).unwrap();
+    std::fs::write(root.join("htm/index.htm"), "Old school").unwrap();
+    std::fs::write(root.join("custom/index.html"), "Default index").unwrap();
+    std::fs::write(root.join("custom/home.html"), "Custom index").unwrap();
+
+    let get = |port| {
+        move |path: &str| {
+            let mut response = String::new();
+            get_path(path, port).read_to_string(&mut response).unwrap();
+            response
         }
+    };
 
-        assert!(
-            server.child.try_wait().unwrap().is_none(),
-            "TOCTOU is not patched server-side!"
-        );
+    let mut server = getserver(&["--root", root.to_str().unwrap()]);
+    let default_index = get(server.port);
+    let htm = default_index("/htm/");
+    let html = default_index("/custom/");
+    server.child.kill().unwrap();
+
+    let mut server = getserver(&["--root", root.to_str().unwrap(), "--index", "home.html"]);
+    let custom_index = get(server.port);
+    let custom = custom_index("/custom/");
+    let fallback = custom_index("/htm/");
+    server.child.kill().unwrap();
+
+    assert!(htm.ends_with("\r\n\r\nOld school"));
+    assert!(html.ends_with("\r\n\r\nDefault index"));
+    assert!(custom.ends_with("\r\n\r\nCustom index"));
+    // No configured index file in there, so it gets a listing
+    assert!(fallback.contains("index.htm"));
+}
+
+#[test]
+pub fn test_index_negotiation() {
+    let root = temp_root("index_negotiation");
+    std::fs::create_dir_all(root.join("html_only")).unwrap();
+    std::fs::write(root.join("index.html"), "<p>Hi</p>").unwrap();
+    std::fs::write(root.join("index.json"), "{\"hi\":true}").unwrap();
+    std::fs::write(root.join("html_only/index.html"), "<p>Only</p>").unwrap();
+    let server = start_server(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--index",
+        "index.html,index.json",
+    ]);
+
+    let get = |path: &str, accept: &str| {
+        let mut response = String::new();
+        request_with_headers("GET", path, &[accept], server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+    let json = get("/", "Accept: application/json");
+    let browser = get(
+        "/",
+        "Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+    );
+    let anything = get("/", "Accept: */*");
+    let prefers_json = get("/", "Accept: text/*;q=0.5, application/*");
+    let no_match = get("/html_only/", "Accept: application/json");
+    let refuses_html = get("/", "Accept: text/html;q=0");
+    let refuses_both = get("/", "Accept: text/html;q=0, application/json;q=0");
+    server.stop();
+
+    assert!(json.ends_with("\r\n\r\n{\"hi\":true}"));
+    assert!(json.contains("\r\nVary: Accept\r\n"));
+    assert!(browser.ends_with("\r\n\r\n<p>Hi</p>"));
+    assert!(anything.ends_with("\r\n\r\n<p>Hi</p>"));
+    assert!(prefers_json.ends_with("\r\n\r\n{\"hi\":true}"));
+    assert!(no_match.ends_with("\r\n\r\n<p>Only</p>"));
+    assert!(!no_match.contains("\r\nVary: Accept\r\n"));
+    assert!(refuses_html.ends_with("\r\n\r\n{\"hi\":true}"));
+    assert!(refuses_both.ends_with("\r\n\r\n<p>Hi</p>"));
+}
+
+#[test]
+pub fn test_empty_requests() {
+    let server = getserver(&[]);
+
+    let send = |request: &[u8]| {
+        let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+        conn.write_all(request).unwrap();
+        conn.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        response
+    };
+    let nothing = send(b"");
+    let blank = send(b"\r\n\r\n");
+    let leading_blank = send(b"\r\nGET /Cargo.toml HTTP/1.0\r\n\r\n");
+    let log = server.stop();
+
+    assert!(nothing.is_empty());
+    assert!(blank.is_empty());
+    assert!(leading_blank.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(!log.contains("Malformed"));
+}
+
+#[test]
+pub fn test_host_required() {
+    let server = start_server(&[]);
+
+    let send = |request: &[u8]| {
+        let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+        conn.write_all(request).unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        response
+    };
+    let missing = send(b"GET /Cargo.toml HTTP/1.1\r\n\r\n");
+    let present = send(b"GET /Cargo.toml HTTP/1.1\r\nhost: localhost\r\nConnection: close\r\n\r\n");
+    let old = send(b"GET /Cargo.toml HTTP/1.0\r\n\r\n");
+    server.stop();
+
+    assert!(missing.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(present.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(old.starts_with("HTTP/1.0 200 OK\r\n"));
+}
+
+#[test]
+pub fn test_vhosts() {
+    let root = temp_root("vhosts");
+    for site in ["default", "one", "two"] {
+        std::fs::create_dir_all(root.join(site)).unwrap();
+        std::fs::write(root.join(site).join("index.html"), site).unwrap();
     }
+    let site = |name: &str| root.join(name).to_str().unwrap().to_string();
+    let args = [
+        "--root".to_string(),
+        site("default"),
+        "--vhost".to_string(),
+        format!("one.test={}", site("one")),
+        "--vhost".to_string(),
+        format!("two.test={}", site("two")),
+    ];
+    let args: Vec<_> = args.iter().map(String::as_str).collect();
+
+    let get = |port, host: &str| {
+        let mut response = String::new();
+        request_with_headers("GET", "/", &[&format!("Host: {host}")], port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+
+    let mut server = getserver(&args);
+    let one = get(server.port, "one.test");
+    let two = get(server.port, "TWO.test:8080");
+    let unknown = get(server.port, "three.test");
+    server.child.kill().unwrap();
 
+    let mut server = getserver(&[args.as_slice(), &["--strict-vhosts"]].concat());
+    let strict_known = get(server.port, "one.test");
+    let strict_unknown = get(server.port, "three.test");
     server.child.kill().unwrap();
+
+    assert!(one.ends_with("\r\n\r\none"));
+    assert!(two.ends_with("\r\n\r\ntwo"));
+    assert!(unknown.ends_with("\r\n\r\ndefault"));
+    assert!(strict_known.ends_with("\r\n\r\none"));
+    assert!(strict_unknown.starts_with("HTTP/1.0 404 Not Found\r\n"));
 }
 
 #[test]
-/// Ported from `exploit-0.1.0.py`
-pub fn test_incorrect_connection_handling() {
-    let mut server = getserver(&["--testing"]);
+pub fn test_split_request() {
+    let server = start_server(&[]);
 
     let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    conn.write_all(b"GET /src/dirlist.html HT").unwrap();
     conn.flush().unwrap();
-    conn.shutdown(Shutdown::Both).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    conn.write_all(b"TP/1.0\r\nUser-Agent: test\r\n\r\n")
+        .unwrap();
+
+    let mut response = String::new();
+    let _ = conn.read_to_string(&mut response);
+
+    server.stop();
 
     assert!(
-        server.child.try_wait().unwrap().is_none(),
-        "Connection handling bug is not patched server-side!"
+        response.starts_with("HTTP/1.0 200 OK\r\n"),
+        "Split request was not handled:\n{response}"
     );
+}
 
-    server.child.kill().unwrap();
+#[test]
+pub fn test_headers_too_large() {
+    let server = start_server(&[]);
+
+    let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    let _ = conn.write_all(
+        format!(
+            "GET / HTTP/1.0\r\nCookie: {}\r\n\r\n",
+            "a".repeat(16 * 1024)
+        )
+        .as_bytes(),
+    );
+
+    let mut response = String::new();
+    let _ = conn.read_to_string(&mut response);
+
+    server.stop();
+
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
 }
 
 #[test]
-/// Ported from `exploit-2.2.0.sh`
-pub fn test_exitflag_off() {
-    let mut server = getserver(&[]);
+pub fn test_max_header_bytes() {
+    let server = start_server(&["--max-header-bytes", "2048"]);
+
+    let send = |cookie_size: usize| {
+        let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+        let _ = conn.write_all(
+            format!(
+                "GET /Cargo.toml HTTP/1.0\r\nCookie: {}\r\n\r\n",
+                "a".repeat(cookie_size)
+            )
+            .as_bytes(),
+        );
+        let mut response = String::new();
+        let _ = conn.read_to_string(&mut response);
+        response
+    };
+    let small = send(1500);
+    // Arrives in one piece, ending well within the read that crosses the limit
+    let large = send(2100);
+    server.stop();
+
+    assert!(small.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(large.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+}
+
+#[test]
+pub fn test_max_uri_length() {
+    let server = start_server(&["--max-uri-length", "100"]);
+    let get = |path: &str| {
+        let mut response = String::new();
+        let _ = get_path(path, server.port).read_to_string(&mut response);
+        response
+    };
+    let short = get(&format!("/Cargo.toml?{}", "a".repeat(80)));
+    let long = get(&format!("/Cargo.toml?{}", "a".repeat(100)));
+    server.stop();
+
+    assert!(short.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(long.starts_with("HTTP/1.0 414 URI Too Long\r\n"));
+}
+
+#[test]
+pub fn test_max_uri_length_default() {
+    // With the default limits a long target overflows the header block too, but it should still be a 414
+    let server = start_server(&[]);
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    let _ = write!(
+        stream,
+        "GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "a".repeat(9000)
+    );
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    server.stop();
+
+    assert!(response.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+}
+
+#[test]
+pub fn test_overlong_file_name() {
+    // Too long for any filesystem, which is the client's doing, not a server error
+    let server = getserver(&[]);
+    let mut response = String::new();
+    let _ = get_path(&format!("/{}", "a".repeat(8000)), server.port).read_to_string(&mut response);
+    let log = server.stop();
+
+    assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(log.contains(" - 404"));
+}
+
+#[test]
+pub fn test_dir_listing_escaping() {
+    let root = temp_root("dir_listing_escaping");
+    std::fs::write(root.join("\"><img src=x onerror=alert(1)>.txt"), "").unwrap();
+
+    let server = start_server(&["--root", root.to_str().unwrap()]);
 
-    let _conn = get_path("/exit", server.port);
+    let mut response = String::new();
+    let _ = get_path("/", server.port).read_to_string(&mut response);
+
+    server.stop();
+    std::fs::remove_dir_all(&root).unwrap();
 
     assert!(
-        server.child.try_wait().unwrap().is_none(),
-        "EXITFLAG is enabled."
+        response.contains("&quot;&gt;&lt;img src=x onerror=alert(1)&gt;.txt"),
+        "File name was not escaped:\n{response}"
     );
+    assert!(!response.contains("<img"));
+}
+
+#[test]
+pub fn test_dir_listing_special_names() {
+    let root = temp_root("dir_listing_special_names");
+    std::fs::create_dir(root.join("empty dir")).unwrap();
+    std::fs::write(root.join("100% {lis} & more.txt"), "").unwrap();
+    std::fs::write(root.join("ünïcödé.txt"), "").unwrap();
+
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+    let mut listing = String::new();
+    let _ = get_path("/", server.port).read_to_string(&mut listing);
+    let mut empty = String::new();
+    let _ = get_path("/empty%20dir/", server.port).read_to_string(&mut empty);
+    server.stop();
+
+    assert!(listing.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(listing.contains(
+        "<a href=\"/100%25%20%7Blis%7D%20%26%20more.txt\">100% {lis} &amp; more.txt</a>"
+    ));
+    assert!(listing.contains(">ünïcödé.txt</a>"));
+    assert!(listing.contains("<a href=\"/empty%20dir/\">empty dir/</a>"));
+    assert!(empty.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(empty.contains("<h1>Directory listing of /empty dir/:</h1>"));
+}
+
+#[test]
+pub fn test_json_listing() {
+    let root = temp_root("json_listing");
+    std::fs::create_dir(root.join("sub")).unwrap();
+    std::fs::write(root.join("say \"hi\".txt"), "12345").unwrap();
+
+    let mut server = getserver(&["--root", root.to_str().unwrap()]);
+    let mut json = String::new();
+    request_with_headers("GET", "/", &["Accept: application/json"], server.port)
+        .read_to_string(&mut json)
+        .unwrap();
+    let mut html = String::new();
+    get_path("/", server.port)
+        .read_to_string(&mut html)
+        .unwrap();
+    server.child.kill().unwrap();
+
+    let mut server = getserver(&["--root", root.to_str().unwrap(), "--list-json"]);
+    let mut always = String::new();
+    get_path("/sub/", server.port)
+        .read_to_string(&mut always)
+        .unwrap();
+    server.child.kill().unwrap();
+
+    let (head, body) = json.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains("\r\nContent-Type: application/json\r\n"));
+    assert!(json.contains("\r\nVary: Accept\r\n"));
+    assert!(body.starts_with("[{\"name\":\"sub\",\"is_dir\":true,\"size\":null,\"modified\":\"20"));
+    assert!(body.contains(
+        "},{\"name\":\"say \\\"hi\\\".txt\",\"is_dir\":false,\"size\":5,\"modified\":\"20"
+    ));
+    assert!(body.ends_with("Z\"}]"));
+    assert!(html.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+    assert!(always.contains("\r\nContent-Type: application/json\r\n"));
+    assert!(!always.contains("\r\nVary: Accept\r\n"));
+    assert!(always.ends_with("\r\n\r\n[]"));
+}
+
+#[test]
+pub fn test_listing_template() {
+    let root = temp_root("listing_template");
+    std::fs::create_dir(root.join("site")).unwrap();
+    std::fs::write(root.join("site").join("a.txt"), "a").unwrap();
+    let template = root.join("template.html");
+    std::fs::write(
+        &template,
+        "<style>body { color: red; }</style><h1>Files in {directory}</h1><ul>{lis}</ul>",
+    )
+    .unwrap();
+    let broken = root.join("broken.html");
+    std::fs::write(&broken, "<h1>{directory}</h1>").unwrap();
+    let site = root.join("site");
+
+    let mut server = getserver(&[
+        "--root",
+        site.to_str().unwrap(),
+        "--listing-template",
+        template.to_str().unwrap(),
+    ]);
+    let mut listing = String::new();
+    let _ = get_path("/", server.port).read_to_string(&mut listing);
+    server.child.kill().unwrap();
+
+    let port = port_check::free_local_ipv4_port().unwrap().to_string();
+    let check = Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+        .args([
+            "127.0.0.1",
+            &port,
+            "--check",
+            "--root",
+            site.to_str().unwrap(),
+        ])
+        .args(["--listing-template", broken.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    assert!(listing.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(listing.contains("<style>body { color: red; }</style><h1>Files in /</h1><ul>"));
+    assert!(listing.contains("a.txt"));
+    assert!(!check.status.success());
+}
+
+#[test]
+pub fn test_no_dir_listing() {
+    let root = temp_root("no_dir_listing");
+    std::fs::create_dir(root.join("subdir")).unwrap();
+    std::fs::write(root.join("subdir/file.txt"), "Still served").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap(), "--no-dir-listing"]);
+
+    let mut root_listing = String::new();
+    get_path("/", server.port)
+        .read_to_string(&mut root_listing)
+        .unwrap();
+    let mut sub_listing = String::new();
+    get_path("/subdir/", server.port)
+        .read_to_string(&mut sub_listing)
+        .unwrap();
+    let mut file = String::new();
+    get_path("/subdir/file.txt", server.port)
+        .read_to_string(&mut file)
+        .unwrap();
+
+    server.stop();
+
+    assert!(root_listing.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(sub_listing.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(!sub_listing.contains("file.txt"));
+    assert!(file.ends_with("Still served"));
+}
+
+#[test]
+pub fn test_nested_dir_listing() {
+    let root = temp_root("nested_dir_listing");
+    std::fs::create_dir_all(root.join("a/b")).unwrap();
+    std::fs::write(root.join("a/b/c.txt"), "Deep down").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let get = |path: &str| {
+        let mut response = String::new();
+        get_path(path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+    let href = |listing: &str| {
+        let start = listing.find("href=\"").unwrap() + 6;
+        let end = start + listing[start..].find('"').unwrap();
+        listing[start..end].to_string()
+    };
+
+    let root_href = href(&get("/"));
+    let a_href = href(&get("/a/"));
+    let b_href = href(&get("/a/b/"));
+    let file = get(&b_href);
+
+    server.stop();
+
+    assert_eq!(root_href, "/a/");
+    assert_eq!(a_href, "/a/b/");
+    assert_eq!(b_href, "/a/b/c.txt");
+    assert!(file.ends_with("\r\n\r\nDeep down"));
+}
+
+#[test]
+pub fn test_blacklist_hidden_from_listing() {
+    let root = temp_root("blacklist_listing");
+    std::fs::write(root.join("public.txt"), "Public").unwrap();
+    std::fs::write(root.join("secret.txt"), "Secret").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap(), "-b", "secret.txt"]);
+
+    let mut listing = String::new();
+    get_path("/", server.port)
+        .read_to_string(&mut listing)
+        .unwrap();
+    let mut secret = String::new();
+    get_path("/secret.txt", server.port)
+        .read_to_string(&mut secret)
+        .unwrap();
+
+    server.stop();
+
+    assert!(listing.contains("public.txt"));
+    assert!(!listing.contains("secret.txt"));
+    assert!(secret.starts_with("HTTP/1.0 404 Not Found\r\n"));
+}
+
+#[test]
+pub fn test_trailing_slash_redirect() {
+    let root = temp_root("trailing_slash_redirect");
+    std::fs::create_dir_all(root.join("my dir")).unwrap();
+    std::fs::create_dir_all(root.join("site")).unwrap();
+    std::fs::write(root.join("site/index.html"), "Site").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let get = |path: &str| {
+        let mut response = String::new();
+        get_path(path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+
+    let listing = get("/my%20dir");
+    let index = get("/site");
+    let with_slash = get("/site/");
+
+    server.stop();
+
+    assert!(listing.starts_with("HTTP/1.0 301 Moved Permanently\r\n"));
+    assert!(listing.contains("Location: /my%20dir/\r\n"));
+    assert!(index.starts_with("HTTP/1.0 301 Moved Permanently\r\n"));
+    assert!(index.contains("Location: /site/\r\n"));
+    assert!(with_slash.ends_with("\r\n\r\nSite"));
+}
+
+#[test]
+pub fn test_percent_decoding() {
+    let root = temp_root("percent_decoding");
+    std::fs::write(root.join("my file.txt"), "decoded").unwrap();
+
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let mut decoded = String::new();
+    let _ = get_path("/my%20file.txt", server.port).read_to_string(&mut decoded);
+
+    let mut malformed = String::new();
+    let _ = get_path("/my%2", server.port).read_to_string(&mut malformed);
+
+    let mut escape = String::new();
+    let _ = get_path("/%2e%2e/", server.port).read_to_string(&mut escape);
+
+    server.stop();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert!(decoded.ends_with("\r\n\r\ndecoded"));
+    assert!(malformed.starts_with("HTTP/1.0 400 Bad Request\r\n"));
+    assert!(escape.starts_with("HTTP/1.0 404 Not Found\r\n"));
+}
+
+#[test]
+pub fn test_proxy_style_targets() {
+    let server = getserver(&[]);
+    let send = |request: &[u8]| {
+        let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+        conn.write_all(request).unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let absolute = send(b"GET http://127.0.0.1/Cargo.toml HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n");
+    let authority = send(b"CONNECT evil.example:443 HTTP/1.1\r\nHost: evil.example\r\n\r\n");
+    let log = server.stop();
+
+    assert!(absolute.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(authority.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(log.contains(
+        "Rejecting absolute-form request target from 127.0.0.1, since this isn't a proxy: http://127.0.0.1/Cargo.toml"
+    ));
+    assert!(log.contains("Rejecting authority-form request target from 127.0.0.1"));
+}
+
+#[test]
+pub fn test_hidden_files() {
+    let root = temp_root("hidden_files");
+    std::fs::write(root.join(".env"), "SECRET").unwrap();
+    std::fs::create_dir(root.join(".git")).unwrap();
+    std::fs::write(root.join(".git").join("config"), "SECRET").unwrap();
+    std::fs::create_dir(root.join(".well-known")).unwrap();
+    std::fs::write(root.join(".well-known").join("challenge.txt"), "token").unwrap();
+    std::fs::write(root.join("visible.txt"), "visible").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(root.join(".env"), root.join("env.txt")).unwrap();
+
+    let get = |port, path| {
+        let mut response = String::new();
+        get_path(path, port).read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let mut server = getserver(&["--root", root.to_str().unwrap()]);
+    let blocked = [
+        "/.env",
+        "/.git/config",
+        "/.git/",
+        "/%2Egit/config",
+        "/.well-known/../.env",
+        #[cfg(unix)]
+        "/env.txt",
+    ]
+    .map(|path| (path, get(server.port, path)));
+    let well_known = get(server.port, "/.well-known/challenge.txt");
+    let listing = get(server.port, "/");
+    server.child.kill().unwrap();
+
+    let mut server = getserver(&["--root", root.to_str().unwrap(), "--serve-hidden-files"]);
+    let served = get(server.port, "/.git/config");
+    let full_listing = get(server.port, "/");
+    server.child.kill().unwrap();
+
+    for (path, response) in blocked {
+        assert!(
+            response.starts_with("HTTP/1.0 404 Not Found\r\n"),
+            "{path} was served:\n{response}"
+        );
+    }
+    assert!(well_known.ends_with("\r\n\r\ntoken"));
+    assert!(listing.contains(">visible.txt</a>"));
+    assert!(listing.contains(">.well-known/</a>"));
+    assert!(!listing.contains(".env"));
+    assert!(!listing.contains(".git"));
+    assert!(served.ends_with("\r\n\r\nSECRET"));
+    assert!(full_listing.contains(">.env</a>"));
+    assert!(full_listing.contains(">.git/</a>"));
+}
+
+#[test]
+pub fn test_encoded_slashes() {
+    let root = temp_root("encoded_slashes");
+    std::fs::create_dir(root.join("sub")).unwrap();
+    std::fs::write(root.join("sub").join("page.txt"), "page").unwrap();
+    #[cfg(unix)]
+    std::fs::write(root.join("what?.txt"), "question").unwrap();
+
+    let mut server = getserver(&["--root", root.to_str().unwrap()]);
+    let get = |path| {
+        let mut response = String::new();
+        get_path(path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+
+    // An encoded slash separates segments like a plain one
+    let encoded = get("/sub%2Fpage.txt");
+    let lowercase = get("/sub%2fpage.txt");
+    // The query string is cut off before decoding, so nothing in it reaches the path
+    let query = get("/sub/page.txt?next=%2F..%2F..%2Fsecret");
+    #[cfg(unix)]
+    let question = get("/what%3F.txt");
+    server.child.kill().unwrap();
+
+    assert!(encoded.ends_with("\r\n\r\npage"));
+    assert!(lowercase.ends_with("\r\n\r\npage"));
+    assert!(query.ends_with("\r\n\r\npage"));
+    // But an encoded question mark is part of the path
+    #[cfg(unix)]
+    assert!(question.ends_with("\r\n\r\nquestion"));
+}
+
+#[test]
+pub fn test_custom_404() {
+    let root = temp_root("custom_404");
+    std::fs::write(root.join("404.html"), "<h1>Nothing here</h1>").unwrap();
+
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let mut response = String::new();
+    let _ = get_path("/missing", server.port).read_to_string(&mut response);
+
+    server.stop();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+    assert!(response.ends_with("\r\n\r\n<h1>Nothing here</h1>"));
+}
+
+#[test]
+pub fn test_default_charset() {
+    let root = temp_root("default_charset");
+    std::fs::write(root.join("page.html"), "<p>Grüße</p>").unwrap();
+    std::fs::write(root.join("data.json"), "{}").unwrap();
+    std::fs::write(root.join("image.png"), [0x89, b'P', b'N', b'G']).unwrap();
+
+    let get = |port, path: &str| {
+        let mut response = Vec::new();
+        get_path(path, port).read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    };
+    let mut server = getserver(&["--root", root.to_str().unwrap()]);
+    let html = get(server.port, "/page.html");
+    let json = get(server.port, "/data.json");
+    let png = get(server.port, "/image.png");
+    server.child.kill().unwrap();
+
+    let mut server = getserver(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--default-charset",
+        "iso-8859-1",
+    ]);
+    let latin1 = get(server.port, "/page.html");
+    server.child.kill().unwrap();
+
+    let mut server = getserver(&["--root", root.to_str().unwrap(), "--default-charset", ""]);
+    let none = get(server.port, "/page.html");
+    server.child.kill().unwrap();
+
+    assert!(html.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+    assert!(json.contains("\r\nContent-Type: application/json; charset=utf-8\r\n"));
+    assert!(png.contains("\r\nContent-Type: image/png\r\n"));
+    assert!(latin1.contains("\r\nContent-Type: text/html; charset=iso-8859-1\r\n"));
+    assert!(none.contains("\r\nContent-Type: text/html\r\n"));
+}
+
+#[test]
+pub fn test_cache_max_age() {
+    let root = temp_root("cache_max_age");
+    std::fs::write(root.join("style.css"), "p {}").unwrap();
+    std::fs::create_dir(root.join("dir")).unwrap();
+
+    let get = |port, path: &str| {
+        let mut response = String::new();
+        let _ = get_path(path, port).read_to_string(&mut response);
+        response
+    };
+    let mut server = getserver(&["--root", root.to_str().unwrap()]);
+    let default = get(server.port, "/style.css");
+    server.child.kill().unwrap();
+
+    let mut server = getserver(&["--root", root.to_str().unwrap(), "--cache-max-age", "3600"]);
+    let file = get(server.port, "/style.css");
+    let listing = get(server.port, "/dir/");
+    let missing = get(server.port, "/missing");
+    server.child.kill().unwrap();
+
+    assert!(!default.contains("Cache-Control"));
+    assert!(file.contains("\r\nCache-Control: public, max-age=3600\r\n"));
+    assert!(listing.contains("\r\nCache-Control: no-store\r\n"));
+    assert!(missing.starts_with("HTTP/1.0 404 "));
+    assert!(missing.contains("\r\nCache-Control: no-store\r\n"));
+}
+
+#[test]
+pub fn test_immutable_pattern() {
+    let root = temp_root("immutable_pattern");
+    std::fs::write(root.join("app.3f9a2c.js"), "run()").unwrap();
+    std::fs::write(root.join("app.js"), "run()").unwrap();
+
+    let get = |port, path: &str| {
+        let mut response = String::new();
+        let _ = get_path(path, port).read_to_string(&mut response);
+        response
+    };
+    let server = start_server(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--cache-max-age",
+        "60",
+        "--immutable-pattern",
+        r"\.[0-9a-f]{6,}\.",
+    ]);
+    let hashed = get(server.port, "/app.3f9a2c.js");
+    let plain = get(server.port, "/app.js");
+    server.stop();
+
+    assert!(hashed.contains("\r\nCache-Control: public, max-age=31536000, immutable\r\n"));
+    assert!(plain.contains("\r\nCache-Control: public, max-age=60\r\n"));
+}
+
+#[test]
+pub fn test_max_rate() {
+    let root = temp_root("max_rate");
+    std::fs::write(root.join("big.bin"), vec![0; 16 * 1024]).unwrap();
+    std::fs::write(root.join("small.txt"), "small").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap(), "--max-rate", "8K"]);
+
+    let timed = |path: &str| {
+        let started = Instant::now();
+        let mut response = Vec::new();
+        get_path(path, server.port)
+            .read_to_end(&mut response)
+            .unwrap();
+        (started.elapsed(), response.len())
+    };
+    let (big, big_len) = timed("/big.bin");
+    let (small, _) = timed("/small.txt");
+    server.stop();
+
+    // 16K at 8K a second is two seconds, give or take the head
+    assert!(big_len > 16 * 1024);
+    assert!(big >= Duration::from_millis(1500));
+    assert!(small < Duration::from_millis(500));
+}
+
+#[test]
+pub fn test_redirects() {
+    let mut server = getserver(&[
+        "--redirect",
+        "/old.html=/Cargo.toml",
+        "--temporary-redirect",
+        "/sale=https://example.com/sale",
+    ]);
+    let get = |path: &str| {
+        let mut response = String::new();
+        let _ = get_path(path, server.port).read_to_string(&mut response);
+        response
+    };
+    let permanent = get("/old.html");
+    let temporary = get("/sale");
+    let prefix = get("/old.html/more");
+    server.child.kill().unwrap();
+
+    let port = port_check::free_local_ipv4_port().unwrap().to_string();
+    let invalid = Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+        .args([
+            "127.0.0.1",
+            &port,
+            "--check",
+            "--redirect",
+            "old.html=/new.html",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(permanent.starts_with("HTTP/1.0 301 Moved Permanently\r\n"));
+    assert!(permanent.contains("\r\nLocation: /Cargo.toml\r\n"));
+    assert!(temporary.starts_with("HTTP/1.0 302 Found\r\n"));
+    assert!(temporary.contains("\r\nLocation: https://example.com/sale\r\n"));
+    assert!(prefix.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(!invalid.status.success());
+}
+
+#[test]
+pub fn test_range() {
+    let root = temp_root("range");
+    std::fs::write(root.join("digits.txt"), "0123456789").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let get_range = |range: &str| {
+        let mut response = String::new();
+        request_with_headers("GET", "/digits.txt", &[range], server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+
+    let partial = get_range("Range: bytes=2-5");
+    let suffix = get_range("range: bytes=-3");
+    let open_ended = get_range("Range: bytes=7-");
+    let unsatisfiable = get_range("Range: bytes=10-");
+    let multiple = get_range("Range: bytes=0-1,4-5");
+
+    server.stop();
+
+    assert!(partial.starts_with("HTTP/1.0 206 Partial Content\r\n"));
+    assert!(partial.contains("Content-Range: bytes 2-5/10\r\n"));
+    assert!(partial.contains("Content-Length: 4\r\n"));
+    assert!(partial.ends_with("\r\n\r\n2345"));
+    assert!(suffix.ends_with("\r\n\r\n789"));
+    assert!(open_ended.contains("Content-Range: bytes 7-9/10\r\n"));
+    assert!(open_ended.ends_with("\r\n\r\n789"));
+    assert!(unsatisfiable.starts_with("HTTP/1.0 416 Range Not Satisfiable\r\n"));
+    assert!(unsatisfiable.contains("Content-Range: bytes */10\r\n"));
+    // Multiple ranges aren't supported, so the whole file is sent
+    assert!(multiple.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(multiple.ends_with("\r\n\r\n0123456789"));
+}
+
+#[test]
+pub fn test_if_modified_since() {
+    let root = temp_root("if_modified_since");
+    std::fs::write(root.join("page.html"), "Cached").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let get_since = |since: &str| {
+        let mut response = String::new();
+        request_with_headers("GET", "/page.html", &[since], server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+
+    let mut first = String::new();
+    get_path("/page.html", server.port)
+        .read_to_string(&mut first)
+        .unwrap();
+    let last_modified = first
+        .lines()
+        .find_map(|line| line.strip_prefix("Last-Modified: "))
+        .unwrap()
+        .to_string();
+
+    let unchanged = get_since(&format!("If-Modified-Since: {last_modified}"));
+    let changed = get_since("If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT");
+    let invalid = get_since("If-Modified-Since: yesterday");
+
+    server.stop();
+
+    assert!(last_modified.ends_with(" GMT"));
+    assert!(unchanged.starts_with("HTTP/1.0 304 Not Modified\r\n"));
+    assert!(unchanged.ends_with("\r\n\r\n"));
+    assert!(changed.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(changed.ends_with("Cached"));
+    assert!(invalid.starts_with("HTTP/1.0 200 OK\r\n"));
+}
+
+#[test]
+pub fn test_etag() {
+    let root = temp_root("etag");
+    std::fs::write(root.join("page.html"), "Tagged").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let get_with = |header: &str| {
+        let mut response = String::new();
+        request_with_headers("GET", "/page.html", &[header], server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+
+    let mut first = String::new();
+    get_path("/page.html", server.port)
+        .read_to_string(&mut first)
+        .unwrap();
+    let mut second = String::new();
+    get_path("/page.html", server.port)
+        .read_to_string(&mut second)
+        .unwrap();
+    let etag = first
+        .lines()
+        .find_map(|line| line.strip_prefix("ETag: "))
+        .unwrap()
+        .to_string();
+
+    let matching = get_with(&format!("If-None-Match: \"other\", {etag}"));
+    let weak = get_with(&format!("If-None-Match: W/{etag}"));
+    let other = get_with("If-None-Match: \"other\"");
+    // A mismatched ETag wins over a matching date
+    let both = get_with(&format!(
+        "If-None-Match: \"other\"\nIf-Modified-Since: {}",
+        first
+            .lines()
+            .find_map(|line| line.strip_prefix("Last-Modified: "))
+            .unwrap()
+    ));
+
+    server.stop();
+
+    assert!(etag.starts_with('"') && etag.ends_with('"'));
+    assert!(second.contains(&format!("ETag: {etag}\r\n")));
+    assert!(matching.starts_with("HTTP/1.0 304 Not Modified\r\n"));
+    assert!(matching.contains(&format!("ETag: {etag}\r\n")));
+    assert!(weak.starts_with("HTTP/1.0 304 Not Modified\r\n"));
+    assert!(other.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(both.starts_with("HTTP/1.0 200 OK\r\n"));
+}
+
+#[test]
+pub fn test_gzip() {
+    let root = temp_root("gzip");
+    let css = "body { color: black; }\n".repeat(200);
+    std::fs::write(root.join("style.css"), &css).unwrap();
+    std::fs::write(root.join("small.css"), "p {}").unwrap();
+    std::fs::write(root.join("image.png"), vec![0; 4096]).unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let get_encoded = |path: &str, accept: &str| {
+        let mut response = Vec::new();
+        request_with_headers("GET", path, &[accept], server.port)
+            .read_to_end(&mut response)
+            .unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let body = response.split_off(split);
+        (String::from_utf8(response).unwrap(), body)
+    };
+
+    let (gzip_head, gzip_body) = get_encoded("/style.css", "Accept-Encoding: deflate, gzip");
+    let (refused_head, refused_body) = get_encoded("/style.css", "Accept-Encoding: gzip;q=0");
+    let (small_head, _) = get_encoded("/small.css", "Accept-Encoding: gzip");
+    let (image_head, _) = get_encoded("/image.png", "Accept-Encoding: gzip");
+
+    server.stop();
+
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(gzip_body.as_slice())
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert!(gzip_head.contains("Content-Encoding: gzip\r\n"));
+    assert!(gzip_head.contains("Vary: Accept-Encoding\r\n"));
+    assert!(gzip_body.len() < css.len());
+    assert_eq!(decoded, css);
+    assert!(!refused_head.contains("Content-Encoding"));
+    assert_eq!(refused_body, css.as_bytes());
+    assert!(!small_head.contains("Content-Encoding"));
+    assert!(!image_head.contains("Content-Encoding"));
+}
+
+#[test]
+pub fn test_repeated_headers() {
+    let root = temp_root("repeated_headers");
+    std::fs::write(root.join("page.html"), "<p>Hi</p>".repeat(500)).unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let mut response = Vec::new();
+    request_with_headers(
+        "GET",
+        "/page.html",
+        &["Accept-Encoding: deflate", "accept-encoding: gzip"],
+        server.port,
+    )
+    .read_to_end(&mut response)
+    .unwrap();
+
+    server.stop();
+
+    assert!(String::from_utf8_lossy(&response).contains("Content-Encoding: gzip\r\n"));
+}
+
+#[test]
+pub fn test_basic_auth() {
+    let server = start_server(&["--auth", "user:secret"]);
+
+    let get_auth = |headers: &[&str]| {
+        let mut response = String::new();
+        request_with_headers("GET", "/Cargo.toml", headers, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+
+    let missing = get_auth(&[]);
+    // user:wrong
+    let wrong = get_auth(&["Authorization: Basic dXNlcjp3cm9uZw=="]);
+    // user:secret
+    let correct = get_auth(&["Authorization: basic dXNlcjpzZWNyZXQ="]);
+
+    server.stop();
+
+    assert!(missing.starts_with("HTTP/1.0 401 Unauthorized\r\n"));
+    assert!(missing.contains("WWW-Authenticate: Basic realm="));
+    assert!(wrong.starts_with("HTTP/1.0 401 Unauthorized\r\n"));
+    assert!(correct.starts_with("HTTP/1.0 200 OK\r\n"));
+}
+
+#[test]
+pub fn test_allow_deny() {
+    let status = |args: &[&str]| {
+        let server = start_server(args);
+        let mut response = String::new();
+        get_path("/Cargo.toml", server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        server.stop();
+        response.lines().next().unwrap_or_default().to_string()
+    };
+
+    let allowed = status(&["--allow", "127.0.0.0/8"]);
+    let not_allowed = status(&["--allow", "10.0.0.0/8", "--allow", "::1"]);
+    let denied = status(&["--allow", "127.0.0.0/8", "--deny", "127.0.0.1"]);
+
+    assert_eq!(allowed, "HTTP/1.0 200 OK");
+    assert_eq!(not_allowed, "HTTP/1.1 403 Forbidden");
+    assert_eq!(denied, "HTTP/1.1 403 Forbidden");
+}
+
+#[test]
+pub fn test_keep_alive() {
+    let root = temp_root("keep_alive");
+    std::fs::write(root.join("a.txt"), "First").unwrap();
+    std::fs::write(root.join("b.txt"), "Second").unwrap();
+    let server = start_server(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--keep-alive-timeout",
+        "1",
+    ]);
+
+    // Pipelined requests on one connection, the last one closing it
+    let mut pipelined = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    pipelined
+        .write_all(
+            b"GET /a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /b.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut responses = String::new();
+    pipelined.read_to_string(&mut responses).unwrap();
+
+    // Waiting for the first response before sending the second request
+    let mut sequential = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    sequential
+        .write_all(b"GET /a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    // The head and body can arrive separately, so read until the whole body is there
+    let mut first = Vec::new();
+    let mut chunk = [0; 1024];
+    while !first.ends_with(b"First") {
+        let read = sequential.read(&mut chunk).unwrap();
+        assert_ne!(read, 0);
+        first.extend_from_slice(&chunk[..read]);
+    }
+    sequential
+        .write_all(b"GET /b.txt HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    // The server closes the connection once it has been idle for a second
+    let mut second = String::new();
+    sequential.read_to_string(&mut second).unwrap();
+
+    server.stop();
+
+    assert_eq!(responses.matches("HTTP/1.1 ").count(), 3);
+    assert!(responses.contains("\r\n\r\nFirst"));
+    assert!(responses.contains("HTTP/1.1 404 Not Found\r\n"));
+    assert!(responses.ends_with("\r\n\r\nSecond"));
+    // Only the response to the closing request says so
+    let last = responses.rfind("HTTP/1.1 ").unwrap();
+    assert_eq!(responses.matches("\r\nConnection: close\r\n").count(), 1);
+    assert!(responses[last..].contains("\r\nConnection: close\r\n"));
+    assert!(String::from_utf8_lossy(&first).ends_with("\r\n\r\nFirst"));
+    assert!(!String::from_utf8_lossy(&first).contains("\r\nConnection: "));
+    assert!(second.ends_with("\r\n\r\nSecond"));
+}
+
+#[test]
+pub fn test_max_keep_alive_requests() {
+    let server = start_server(&["--max-keep-alive-requests", "2"]);
+
+    let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    conn.write_all(
+        b"GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    )
+    .unwrap();
+    let mut responses = String::new();
+    conn.read_to_string(&mut responses).unwrap();
+
+    server.stop();
+
+    assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+    // The client is told the second response is the last
+    let last = responses.rfind("HTTP/1.1 ").unwrap();
+    assert_eq!(responses.matches("\r\nConnection: close\r\n").count(), 1);
+    assert!(responses[last..].contains("\r\nConnection: close\r\n"));
+}
+
+#[test]
+pub fn test_http_1_0_keep_alive() {
+    let root = temp_root("http_1_0_keep_alive");
+    std::fs::write(root.join("a.txt"), "First").unwrap();
+    std::fs::write(root.join("b.txt"), "Second").unwrap();
+    let server = start_server(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--keep-alive-timeout",
+        "1",
+    ]);
+
+    // HTTP/1.0 closes after one response unless the client asks to keep the connection
+    let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    conn.write_all(
+        b"GET /a.txt HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+          GET /b.txt HTTP/1.0\r\n\r\n\
+          GET /a.txt HTTP/1.0\r\n\r\n",
+    )
+    .unwrap();
+    let mut responses = String::new();
+    conn.read_to_string(&mut responses).unwrap();
+
+    server.stop();
+
+    assert_eq!(responses.matches("HTTP/1.0 200 OK\r\n").count(), 2);
+    assert_eq!(
+        responses.matches("\r\nConnection: keep-alive\r\n").count(),
+        1
+    );
+    assert!(responses.ends_with("\r\n\r\nSecond"));
+}
+
+#[test]
+pub fn test_tcp_nodelay() {
+    let server = start_server(&["--tcp-nodelay"]);
+    let mut response = String::new();
+    let _ = get_path("/Cargo.toml", server.port).read_to_string(&mut response);
+    server.stop();
+
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+}
+
+#[test]
+pub fn test_access_log_clf() {
+    let root = temp_root("access_log_clf");
+    let log = std::env::temp_dir().join(format!(
+        "simplewebserver-test-access-{}.log",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&log);
+    std::fs::write(root.join("page.html"), "Hello").unwrap();
+    let server = start_server(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--access-log-format",
+        "clf",
+        "--access-log",
+        log.to_str().unwrap(),
+    ]);
+
+    for path in ["/page.html", "/missing"] {
+        let mut response = String::new();
+        get_path(path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+    }
+    let mut head = String::new();
+    request("HEAD", "/page.html", server.port)
+        .read_to_string(&mut head)
+        .unwrap();
+
+    server.stop();
+
+    let lines = std::fs::read_to_string(&log).unwrap();
+    let lines: Vec<_> = lines.lines().collect();
+    std::fs::remove_file(&log).unwrap();
+
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("127.0.0.1 - - ["));
+    assert!(lines[0].ends_with(" +0000] \"GET /page.html HTTP/1.0\" 200 5"));
+    assert!(lines[1].ends_with("] \"GET /missing HTTP/1.0\" 404 4"));
+    assert!(lines[2].ends_with("] \"HEAD /page.html HTTP/1.0\" 200 -"));
+}
+
+#[test]
+pub fn test_verbose_access() {
+    let server = getserver(&["--verbose-access"]);
+
+    request_with_headers(
+        "GET",
+        "/Cargo.toml",
+        &["User-Agent: curious/1.0", "Referer: http://example.com/"],
+        server.port,
+    )
+    .read_to_string(&mut String::new())
+    .unwrap();
+    get_path("/missing", server.port)
+        .read_to_string(&mut String::new())
+        .unwrap();
+    let log = server.stop();
+
+    assert!(
+        log.contains("127.0.0.1: GET /Cargo.toml - 200 \"http://example.com/\" \"curious/1.0\"")
+    );
+    assert!(log.contains("127.0.0.1: GET /missing - 404 \"-\" \"-\""));
+}
+
+#[test]
+pub fn test_log_ip_privacy() {
+    let server = getserver(&["--log-ip-privacy", "-r", "2", "-v"]);
+
+    for _ in 0..2 {
+        get_path("/missing", server.port)
+            .read_to_string(&mut String::new())
+            .unwrap();
+    }
+    let log = server.stop();
+
+    assert!(log.contains("127.0.0.0: GET /missing - 404"));
+    assert!(log.contains("Rate limiting 127.0.0.0/24 after 2 requests in a minute."));
+    assert!(log.contains("Rejecting request from rate-limited network: 127.0.0.0/24."));
+    assert!(!log.contains("127.0.0.1: "));
+    assert!(!log.contains("127.0.0.1 "));
+}
+
+#[test]
+pub fn test_ratelimiting_1() {
+    let mut server = getserver(&["-r", "3", "-d", "2", "-v"]);
+
+    for _ in 1..=2 {
+        let mut conn = get_path("/", server.port);
+        let mut buf: [u8; 9] = [0; 9];
+        let _ = conn.read(&mut buf).unwrap();
+        assert_eq!(Vec::from(buf), b"HTTP/1.0 ");
+    }
+
+    let mut ratelimited = get_path("/", server.port);
+
+    let mut response = String::new();
+    let _ = ratelimited.read_to_string(&mut response).unwrap();
+
+    server.child.kill().unwrap();
+
+    assert!(response.starts_with("HTTP/1.0 429 Too Many Requests\r\n"));
+    assert!(response.contains("Retry-After: 2\r\n"));
+    assert!(response.ends_with("\r\n\r\n429\n"));
+}
+
+#[test]
+pub fn test_ratelimit_keep_alive() {
+    let server = start_server(&["-r", "3", "-d", "30"]);
+
+    // Every request counts, not just the first one on a connection
+    let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    conn.write_all(&b"GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n".repeat(5))
+        .unwrap();
+    conn.shutdown(Shutdown::Write).unwrap();
+    let mut responses = String::new();
+    conn.read_to_string(&mut responses).unwrap();
+
+    server.stop();
+
+    assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+    assert_eq!(
+        responses
+            .matches("HTTP/1.1 429 Too Many Requests\r\n")
+            .count(),
+        3
+    );
+}
+
+#[test]
+pub fn test_ratelimit_no_keep_alive() {
+    let server = start_server(&["-r", "2", "-d", "30", "--keep-alive-timeout", "0"]);
+    get_path("/Cargo.toml", server.port)
+        .read_to_string(&mut String::new())
+        .unwrap();
+
+    // Without keep-alive a 429 closes the connection like any other response, and says so
+    let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    conn.write_all(&b"GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n".repeat(2))
+        .unwrap();
+    let mut responses = String::new();
+    conn.read_to_string(&mut responses).unwrap();
+    server.stop();
+
+    assert!(responses.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+    assert_eq!(responses.matches("HTTP/1.1 ").count(), 1);
+    assert!(responses.contains("\r\nConnection: close\r\n"));
+}
+
+#[test]
+pub fn test_ratelimit_recovery() {
+    let server = start_server(&["-r", "3", "-d", "1"]);
+    let status = |port| {
+        let mut response = String::new();
+        get_path("/", port).read_to_string(&mut response).unwrap();
+        response[9..12].to_string()
+    };
+
+    assert_eq!(status(server.port), "200");
+    assert_eq!(status(server.port), "200");
+    assert_eq!(status(server.port), "429");
+    let limited = Instant::now();
+
+    // Knocking while in timeout doesn't extend it
+    thread::sleep(Duration::from_millis(500));
+    for _ in 0..5 {
+        assert_eq!(status(server.port), "429");
+    }
+    thread::sleep(Duration::from_millis(1300).saturating_sub(limited.elapsed()));
+
+    // And the counter starts over afterwards
+    let recovered = [status(server.port), status(server.port)];
+    let limited_again = status(server.port);
+    server.stop();
+
+    assert_eq!(recovered, ["200", "200"]);
+    assert_eq!(limited_again, "429");
+}
+
+#[test]
+pub fn test_ratelimit_timeout_ms() {
+    let server = start_server(&["-r", "2", "--timeout-ms", "300"]);
+    let response = |port| {
+        let mut response = String::new();
+        get_path("/", port).read_to_string(&mut response).unwrap();
+        response
+    };
+
+    assert!(response(server.port).starts_with("HTTP/1.0 200 "));
+    let limited = response(server.port);
+    let started = Instant::now();
+    // Less than a second is left, which still has to be rounded up
+    let knocked = response(server.port);
+    thread::sleep(Duration::from_millis(400).saturating_sub(started.elapsed()));
+    let recovered = response(server.port);
+    server.stop();
+
+    assert!(limited.starts_with("HTTP/1.0 429 "));
+    assert!(limited.contains("Retry-After: 1\r\n"));
+    assert!(knocked.contains("Retry-After: 1\r\n"));
+    assert!(recovered.starts_with("HTTP/1.0 200 "));
+}
+
+#[test]
+pub fn test_ratelimit_json() {
+    let mut server = getserver(&["-r", "2", "-d", "30", "--error-format", "json"]);
+
+    let mut response = String::new();
+    get_path("/", server.port)
+        .read_to_string(&mut response)
+        .unwrap();
+    let mut response = String::new();
+    get_path("/", server.port)
+        .read_to_string(&mut response)
+        .unwrap();
+    server.child.kill().unwrap();
+
+    assert!(response.starts_with("HTTP/1.0 429 Too Many Requests\r\n"));
+    assert!(response.contains("\r\nRetry-After: 30\r\n"));
+    assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+    assert!(response.ends_with("\r\n\r\n{\"error\":\"rate_limited\",\"retry_after\":30}"));
+}
+
+#[test]
+pub fn test_ratelimit_accept_json() {
+    let server = start_server(&["-r", "2", "-d", "30", "--single-threaded"]);
+    get_path("/", server.port)
+        .read_to_string(&mut String::new())
+        .unwrap();
+
+    // Hold up the single thread so the next request has fully arrived by the time its connection
+    // gets turned away. The held one's request is what starts the ratelimit.
+    let mut first = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut limited = request_with_headers(
+        "GET",
+        "/",
+        &["Accept: text/html;q=0.5, Application/JSON"],
+        server.port,
+    );
+    thread::sleep(Duration::from_millis(100));
+    first.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    first.read_to_string(&mut String::new()).unwrap();
+
+    let mut response = String::new();
+    limited.read_to_string(&mut response).unwrap();
+    server.stop();
+
+    assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+    assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+    assert!(response.ends_with("{\"error\":\"rate_limited\",\"retry_after\":30}"));
+}
+
+#[test]
+pub fn test_ratelimit_prefix() {
+    let server = getserver(&["-r", "2", "--ratelimit-ipv4-prefix", "8"]);
+
+    for _ in 0..2 {
+        get_path("/", server.port)
+            .read_to_string(&mut String::new())
+            .unwrap();
+    }
+    let log = server.stop();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+        .args(["--ratelimit-ipv4-prefix", "33"])
+        .output()
+        .unwrap()
+        .status;
+
+    assert!(log.contains("Rate limiting 127.0.0.0/8 after 2 requests in a minute."));
+    assert!(!status.success());
+}
+
+#[test]
+pub fn test_trusted_proxy() {
+    let log = std::env::temp_dir().join(format!(
+        "simplewebserver-test-proxy-{}.log",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&log);
+    let server = start_server(&[
+        "-r",
+        "2",
+        "--trusted-proxy",
+        "127.0.0.0/8",
+        "--access-log-format",
+        "clf",
+        "--access-log",
+        log.to_str().unwrap(),
+    ]);
+
+    let statuses: Vec<_> = [
+        "X-Forwarded-For: 203.0.113.5",
+        "X-Forwarded-For: 203.0.113.5",
+        "X-Forwarded-For: 198.51.100.7",
+        // Only the entry our proxy added can be trusted
+        "X-Forwarded-For: 198.51.100.7, 192.0.2.1, 127.0.0.2",
+        "X-Forwarded-For: not an address",
+    ]
+    .iter()
+    .map(|header| {
+        let mut response = String::new();
+        request_with_headers("GET", "/Cargo.toml", &[header], server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response[9..12].to_string()
+    })
+    .collect();
+    server.stop();
+
+    let lines = std::fs::read_to_string(&log).unwrap();
+    let clients: Vec<_> = lines
+        .lines()
+        .map(|line| line.split(' ').next().unwrap())
+        .collect();
+    std::fs::remove_file(&log).unwrap();
+
+    assert_eq!(statuses, ["200", "429", "200", "200", "200"]);
+    assert_eq!(
+        clients,
+        [
+            "203.0.113.5",
+            "203.0.113.5",
+            "198.51.100.7",
+            "192.0.2.1",
+            "127.0.0.1"
+        ]
+    );
+}
+
+#[test]
+pub fn test_untrusted_forwarded_for() {
+    let server = start_server(&["-r", "2"]);
+
+    let statuses: Vec<_> = ["203.0.113.5", "198.51.100.7"]
+        .iter()
+        .map(|client| {
+            let mut response = String::new();
+            request_with_headers(
+                "GET",
+                "/Cargo.toml",
+                &[&format!("X-Forwarded-For: {client}")],
+                server.port,
+            )
+            .read_to_string(&mut response)
+            .unwrap();
+            response[9..12].to_string()
+        })
+        .collect();
+    server.stop();
+
+    assert_eq!(statuses, ["200", "429"]);
+}
+
+#[test]
+pub fn test_https() {
+    let root = temp_root("https");
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert_path, key_path) = (root.join("cert.pem"), root.join("key.pem"));
+    std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+    std::fs::write(&key_path, cert.signing_key.serialize_pem()).unwrap();
+    std::fs::write(root.join("index.html"), "Hello over TLS").unwrap();
+
+    let server = start_server(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--cert",
+        cert_path.to_str().unwrap(),
+        "--key",
+        key_path.to_str().unwrap(),
+    ]);
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert.cert.der().clone()).unwrap();
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connection =
+        rustls::ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+            .unwrap();
+    let mut tls = rustls::StreamOwned::new(
+        connection,
+        TcpStream::connect(("127.0.0.1", server.port)).unwrap(),
+    );
+    tls.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    let _ = tls.read_to_string(&mut response);
+
+    server.stop();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nHello over TLS"));
+}
+
+#[test]
+pub fn test_bind_from_env() {
+    let check = |args: &[&str], port: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+            .env_clear()
+            .env("BIND_ADDR", "127.0.0.1")
+            .env("PORT", port)
+            .args(args)
+            .arg("--check")
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let from_env = port_check::free_local_ipv4_port().unwrap().to_string();
+    let from_cli = port_check::free_local_ipv4_port().unwrap().to_string();
+
+    let env_only = check(&[], &from_env);
+    // The command line wins
+    let both = check(&["127.0.0.1", &from_cli], &from_env);
+
+    assert!(env_only.contains(&format!("Would serve on: http://127.0.0.1:{from_env}\n")));
+    assert!(both.contains(&format!("Would serve on: http://127.0.0.1:{from_cli}\n")));
+}
+
+#[test]
+#[cfg(unix)]
+pub fn test_graceful_shutdown() {
+    let mut server = getserver(&[]);
+    // Connections are only accepted once the signal handlers are in place
+    get_path("/", server.port)
+        .read_to_string(&mut String::new())
+        .unwrap();
+
+    let status = Command::new("kill")
+        .args(["-TERM", &server.child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    for _ in 0..50 {
+        if let Some(status) = server.child.try_wait().unwrap() {
+            assert!(status.success(), "Server did not exit cleanly: {status}");
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    server.child.kill().unwrap();
+    panic!("Server did not shut down after SIGTERM");
+}
+
+#[test]
+pub fn test_admin_shutdown() {
+    let mut server = getserver(&["--admin-token", "s3cret"]);
+    let send = |token| {
+        let mut response = String::new();
+        request_with_headers(
+            "GET",
+            "/admin/shutdown",
+            &[&format!("X-Admin-Token: {token}")],
+            server.port,
+        )
+        .read_to_string(&mut response)
+        .unwrap();
+        response
+    };
+
+    let wrong = send("guess");
+    let right = send("s3cret");
+
+    let mut exited = None;
+    for _ in 0..50 {
+        exited = server.child.try_wait().unwrap();
+        if exited.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    if exited.is_none() {
+        server.stop();
+    }
+
+    assert!(wrong.starts_with("HTTP/1.0 403 Forbidden\r\n"));
+    assert!(right.starts_with("HTTP/1.0 202 Accepted\r\n"));
+    assert!(exited.is_some_and(|status| status.success()));
+}
+
+#[test]
+pub fn test_no_admin_token() {
+    let mut server = getserver(&[]);
+    let mut response = String::new();
+    request_with_headers("GET", "/admin/shutdown", &["X-Admin-Token: "], server.port)
+        .read_to_string(&mut response)
+        .unwrap();
+    let running = server.child.try_wait().unwrap().is_none();
+    server.child.kill().unwrap();
+
+    // Without a token the endpoint doesn't exist
+    assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(running);
+}
+
+// TEST OLD EXPLOITS
+
+#[test]
+/// Ported from `exploit-0.0.1.sh`
+pub fn test_toctou_patched() {
+    const TOCTOU_TEST_LENGTH: u8 = 5;
+
+    if Path::new("index.html").exists() {
+        println!("index.html already exists!");
+        println!("Consider this a skip.");
+        return;
+    }
+
+    // We disable rate-limiting on the server
+    let mut server = getserver(&["--testing", "-r", "0"]);
+
+    let start = time::OffsetDateTime::now_utc();
+
+    loop {
+        // Equivalent to `touch index.html`
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open("index.html")
+            .unwrap();
+
+        // Here, if following along in the shell script, we are interweaving the curl with the rm
+        let mut conn = get_path("/", server.port);
+
+        // This would be the `sleep 0.0015`
+        thread::sleep(Duration::from_micros(1000));
+
+        std::fs::remove_file("index.html").unwrap();
+
+        // Wait for server to finish processing. (This would be the `wait $pid` line)
+        let _ = conn.read(&mut Vec::new()).unwrap();
+
+        // Break out of the loop if time has expired
+        if time::OffsetDateTime::now_utc() - start
+            > time::Duration::seconds(TOCTOU_TEST_LENGTH as i64)
+        {
+            break;
+        }
+
+        assert!(
+            server.child.try_wait().unwrap().is_none(),
+            "TOCTOU is not patched server-side!"
+        );
+    }
+
+    server.child.kill().unwrap();
+}
+
+#[test]
+/// Ported from `exploit-0.1.0.py`
+pub fn test_incorrect_connection_handling() {
+    let mut server = getserver(&["--testing"]);
+
+    let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    conn.flush().unwrap();
+    conn.shutdown(Shutdown::Both).unwrap();
+
+    assert!(
+        server.child.try_wait().unwrap().is_none(),
+        "Connection handling bug is not patched server-side!"
+    );
+
+    server.child.kill().unwrap();
+}
+
+#[test]
+/// Ported from `exploit-2.2.0.sh`
+pub fn test_exitflag_off() {
+    let mut server = getserver(&[]);
+
+    let mut response = String::new();
+    let _ = get_path("/exit", server.port).read_to_string(&mut response);
+
+    assert!(
+        server.child.try_wait().unwrap().is_none(),
+        "EXITFLAG is enabled."
+    );
+
+    server.child.kill().unwrap();
+}
+
+#[test]
+pub fn test_header_timeout() {
+    let server = start_server(&["--header-timeout", "1"]);
+
+    // Headers that never finish (Slowloris) get a 408 instead of holding the connection open
+    let mut slow = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    slow.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n")
+        .unwrap();
+    let mut response = String::new();
+    slow.read_to_string(&mut response).unwrap();
+
+    // Dribbling bytes doesn't extend the deadline either
+    let mut dribble = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    let mut dribbled = Vec::new();
+    for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\n" {
+        if dribble.write_all(&[*byte]).is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let _ = dribble.read_to_end(&mut dribbled);
+
+    server.stop();
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    assert!(String::from_utf8_lossy(&dribbled).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+}
+
+#[test]
+pub fn test_multiple_binds() {
+    let first = port_check::free_local_ipv4_port().unwrap();
+    let second = port_check::free_local_ipv4_port().unwrap();
+    let (first_bind, second_bind) = (format!("127.0.0.1:{first}"), format!("127.0.0.1:{second}"));
+    let server = getserver(&["--bind", &first_bind, "--bind", &second_bind]);
+
+    let mut responses = [String::new(), String::new()];
+    for (port, response) in [first, second].into_iter().zip(&mut responses) {
+        get_path("/Cargo.toml", port)
+            .read_to_string(response)
+            .unwrap();
+    }
+    let log = server.stop();
+
+    for response in responses {
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    }
+    // --bind replaces the positional address and port
+    assert_eq!(log.matches("Serving on: ").count(), 2);
+}
+
+#[test]
+pub fn test_max_connections() {
+    let server = start_server(&["--max-connections", "1"]);
+
+    // Sits idle on the only slot
+    let held = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut rejected = String::new();
+    get_path("/Cargo.toml", server.port)
+        .read_to_string(&mut rejected)
+        .unwrap();
+
+    // Closing the held connection frees the slot again
+    drop(held);
+    thread::sleep(Duration::from_millis(100));
+    let mut accepted = String::new();
+    get_path("/Cargo.toml", server.port)
+        .read_to_string(&mut accepted)
+        .unwrap();
+
+    server.stop();
+    assert!(rejected.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert!(rejected.contains("\r\nRetry-After: 5\r\n"));
+    assert!(accepted.starts_with("HTTP/1.0 200 OK\r\n"));
+}
+
+#[test]
+pub fn test_max_connections_per_ip() {
+    let server = getserver(&["--max-connections-per-ip", "2", "--metrics-path"]);
+
+    // Sit idle on both of this client's slots
+    let held = [
+        TcpStream::connect(("127.0.0.1", server.port)).unwrap(),
+        TcpStream::connect(("127.0.0.1", server.port)).unwrap(),
+    ];
+    thread::sleep(Duration::from_millis(100));
+    let mut rejected = String::new();
+    get_path("/Cargo.toml", server.port)
+        .read_to_string(&mut rejected)
+        .unwrap();
+
+    // Closing them frees the slots again
+    drop(held);
+    thread::sleep(Duration::from_millis(100));
+    let mut metrics = String::new();
+    get_path("/metrics", server.port)
+        .read_to_string(&mut metrics)
+        .unwrap();
+
+    let log = server.stop();
+    assert!(rejected.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert!(rejected.contains("\r\nRetry-After: 5\r\n"));
+    assert!(metrics.starts_with("HTTP/1.0 200 OK\r\n"));
+    // One client being greedy doesn't mean the server is at capacity
+    assert!(!log.contains("Server is at capacity"));
+    assert!(metrics.contains("\nsimplewebserver_overloaded_total 0\n"));
+}
+
+#[test]
+pub fn test_overload_warning() {
+    let server = getserver(&["--max-connections", "1", "--metrics-path"]);
+
+    let held = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    for _ in 0..3 {
+        get_path("/", server.port)
+            .read_to_string(&mut String::new())
+            .unwrap();
+    }
+    drop(held);
+    thread::sleep(Duration::from_millis(100));
+    let mut metrics = String::new();
+    get_path("/metrics", server.port)
+        .read_to_string(&mut metrics)
+        .unwrap();
+
+    // The other two are summed up once the 5 seconds are over, even though nothing else gets rejected
+    thread::sleep(Duration::from_secs(6));
+    let log = server.stop();
+
+    assert_eq!(log.matches("Server is at capacity").count(), 2);
+    assert!(log.contains(
+        "Server is at capacity (too many open connections). Rejected 1 connection(s) since the last warning."
+    ));
+    assert!(log.contains(
+        "Server is at capacity (too many open connections). Rejected 2 connection(s) since the last warning."
+    ));
+    assert!(metrics.contains("\nsimplewebserver_overloaded_total 3\n"));
+}
+
+#[test]
+pub fn test_health_check() {
+    let root = temp_root("health_check");
+    let mut server = getserver(&["--root", root.to_str().unwrap(), "--auth", "user:pass"]);
+    let mut health = String::new();
+    get_path("/healthz", server.port)
+        .read_to_string(&mut health)
+        .unwrap();
+    server.child.kill().unwrap();
+
+    let mut moved = getserver(&["--health-path", "/up"]);
+    let (mut up, mut old) = (String::new(), String::new());
+    get_path("/up", moved.port).read_to_string(&mut up).unwrap();
+    get_path("/healthz", moved.port)
+        .read_to_string(&mut old)
+        .unwrap();
+    moved.child.kill().unwrap();
+
+    let mut disabled = getserver(&["--health-path", ""]);
+    let mut off = String::new();
+    get_path("/healthz", disabled.port)
+        .read_to_string(&mut off)
+        .unwrap();
+    disabled.child.kill().unwrap();
+
+    // Works with an empty root and without credentials
+    assert!(health.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(health.ends_with("\r\n\r\nok\n"));
+    assert!(up.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(old.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(off.starts_with("HTTP/1.0 404 Not Found\r\n"));
+}
+
+#[test]
+pub fn test_options() {
+    let server = start_server(&[]);
+    let mut responses = Vec::new();
+    for (method, path) in [("OPTIONS", "*"), ("OPTIONS", "/Cargo.toml"), ("GET", "*")] {
+        let mut response = String::new();
+        request(method, path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        responses.push(response);
+    }
+    server.stop();
+
+    for response in &responses[..2] {
+        assert!(response.starts_with("HTTP/1.0 204 No Content\r\n"));
+        assert!(response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+    assert!(responses[2].starts_with("HTTP/1.0 400 Bad Request\r\n"));
+}
+
+#[test]
+pub fn test_cors() {
+    let mut plain = getserver(&[]);
+    let mut without = String::new();
+    request_with_headers(
+        "GET",
+        "/Cargo.toml",
+        &["Origin: https://a.example"],
+        plain.port,
+    )
+    .read_to_string(&mut without)
+    .unwrap();
+    plain.child.kill().unwrap();
+
+    let mut wildcard = getserver(&["--cors-origin", "*"]);
+    let mut any = String::new();
+    request_with_headers(
+        "GET",
+        "/Cargo.toml",
+        &["Origin: https://a.example"],
+        wildcard.port,
+    )
+    .read_to_string(&mut any)
+    .unwrap();
+    wildcard.child.kill().unwrap();
+
+    let mut server = getserver(&[
+        "--cors-origin",
+        "https://a.example",
+        "--cors-methods",
+        "GET,HEAD",
+    ]);
+    let (mut allowed, mut denied, mut preflight) = (String::new(), String::new(), String::new());
+    request_with_headers(
+        "GET",
+        "/Cargo.toml",
+        &["Origin: https://a.example"],
+        server.port,
+    )
+    .read_to_string(&mut allowed)
+    .unwrap();
+    request_with_headers(
+        "GET",
+        "/Cargo.toml",
+        &["Origin: https://b.example"],
+        server.port,
+    )
+    .read_to_string(&mut denied)
+    .unwrap();
+    request_with_headers(
+        "OPTIONS",
+        "/Cargo.toml",
+        &[
+            "Origin: https://a.example",
+            "Access-Control-Request-Method: GET",
+            "Access-Control-Request-Headers: Range",
+        ],
+        server.port,
+    )
+    .read_to_string(&mut preflight)
+    .unwrap();
+    server.child.kill().unwrap();
+
+    assert!(!without.contains("Access-Control-"));
+    assert!(any.contains("\r\nAccess-Control-Allow-Origin: *\r\n"));
+    assert!(allowed.contains("\r\nAccess-Control-Allow-Origin: https://a.example\r\n"));
+    assert!(allowed.contains("\r\nVary: Origin\r\n"));
+    assert!(!denied.contains("Access-Control-"));
+    assert!(preflight.starts_with("HTTP/1.0 204 No Content\r\n"));
+    assert!(preflight.contains("\r\nAccess-Control-Allow-Origin: https://a.example\r\n"));
+    assert!(preflight.contains("\r\nAccess-Control-Allow-Methods: GET, HEAD\r\n"));
+    assert!(preflight.contains("\r\nAccess-Control-Allow-Headers: Range\r\n"));
+}
+
+#[test]
+pub fn test_config_file() {
+    let root = temp_root("config_file");
+    std::fs::write(root.join("hello.txt"), "Hello").unwrap();
+    let config = std::env::temp_dir().join(format!(
+        "simplewebserver-test-config-{}.toml",
+        std::process::id()
+    ));
+    // The port is also given on the command line, which wins
+    std::fs::write(
+        &config,
+        format!(
+            "root = {:?}\nport = 1\nno-server-header = true\nindex = [\"hello.txt\"]\n",
+            root.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let mut server = getserver(&["--config", config.to_str().unwrap()]);
+
+    let mut response = String::new();
+    get_path("/", server.port)
+        .read_to_string(&mut response)
+        .unwrap();
+    server.child.kill().unwrap();
+
+    std::fs::write(&config, "not-an-option = 1\n").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+        .args(["--config", config.to_str().unwrap()])
+        .output()
+        .unwrap()
+        .status;
+    std::fs::remove_file(&config).unwrap();
+
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(!response.contains("\r\nServer: "));
+    assert!(response.ends_with("\r\n\r\nHello"));
+    assert!(!status.success());
+}
+
+#[test]
+pub fn test_config_file_conflicts() {
+    let config = std::env::temp_dir().join(format!(
+        "simplewebserver-test-config-conflicts-{}.toml",
+        std::process::id()
+    ));
+    let mut outputs = Vec::new();
+    for contents in [
+        "file = \"Cargo.toml\"\nroot = \"src\"\n",
+        "key = \"key.pem\"\n",
+    ] {
+        std::fs::write(&config, contents).unwrap();
+        outputs.push(
+            Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+                .args(["--config", config.to_str().unwrap(), "--check"])
+                .output()
+                .unwrap(),
+        );
+    }
+    std::fs::remove_file(&config).unwrap();
+
+    for output in outputs {
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("error:"));
+    }
+}
+
+#[test]
+pub fn test_method_not_allowed() {
+    let server = start_server(&[]);
+    let mut responses = Vec::new();
+    for method in ["POST", "PUT", "DELETE", "PATCH", "get"] {
+        let mut response = String::new();
+        request(method, "/Cargo.toml", server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        responses.push(response);
+    }
+    server.stop();
+
+    let (malformed, unsupported) = responses.split_last().unwrap();
+    for response in unsupported {
+        assert!(response.starts_with("HTTP/1.0 405 Method Not Allowed\r\n"));
+        assert!(response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+    }
+    // Methods are case-sensitive, so that isn't a method at all
+    assert!(malformed.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+}
+
+#[test]
+pub fn test_method_not_allowed_closes() {
+    let server = start_server(&[]);
+    let mut conn = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    conn.write_all(b"POST /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+    server.stop();
+
+    // The server hangs up after this, so a 1.1 client has to be told not to send more
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(response.contains("\r\nConnection: close\r\n"));
+}
+
+#[test]
+pub fn test_single_threaded() {
+    let server = start_server(&["--single-threaded"]);
+    let mut responses = [String::new(), String::new()];
+    for response in &mut responses {
+        get_path("/Cargo.toml", server.port)
+            .read_to_string(response)
+            .unwrap();
+    }
+    server.stop();
+
+    for response in responses {
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    }
+}
+
+#[test]
+pub fn test_control_characters() {
+    let root = temp_root("control_characters");
+    std::fs::write(root.join("page.html"), "Hello").unwrap();
+    let log = std::env::temp_dir().join(format!(
+        "simplewebserver-test-control-{}.log",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&log);
+    let server = start_server(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--access-log-format",
+        "clf",
+        "--access-log",
+        log.to_str().unwrap(),
+    ]);
+
+    let mut rejected = Vec::new();
+    for path in [
+        "/page.html%00.txt",
+        "/%0D%0A127.0.0.1 - - forged",
+        "/\x1b[31m",
+    ] {
+        let mut response = String::new();
+        get_path(path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        rejected.push(response);
+    }
+    // Query strings aren't decoded, but still end up in the access log
+    let mut query = String::new();
+    get_path("/page.html?\x1b[2J", server.port)
+        .read_to_string(&mut query)
+        .unwrap();
+    server.stop();
+
+    let lines = std::fs::read_to_string(&log).unwrap();
+    std::fs::remove_file(&log).unwrap();
+    for response in rejected {
+        assert!(response.starts_with("HTTP/1.0 400 Bad Request\r\n"));
+    }
+    assert!(query.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(!lines.contains('\x1b'));
+    assert!(lines.contains("GET /page.html?\\u{1b}[2J HTTP/1.0"));
+}
+
+#[test]
+/// Every way out of the web root we could think of. None of them may reach the secret.
+pub fn test_directory_escape() {
+    let base = temp_root("directory_escape");
+    let root = base.join("root");
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(base.join("secret.txt"), "SECRET").unwrap();
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(base.join("secret.txt"), root.join("outside.txt")).unwrap();
+        std::os::unix::fs::symlink(&base, root.join("updir")).unwrap();
+    }
+    let mut server = getserver(&["--root", root.to_str().unwrap()]);
+
+    let attempts = [
+        "/../secret.txt",
+        "/../etc/passwd",
+        "/../../../../../../etc/passwd",
+        "/%2e%2e/secret.txt",
+        "/%2E%2E%2Fsecret.txt",
+        "/..%2fsecret.txt",
+        "/..%5csecret.txt",
+        "/sub/../../secret.txt",
+        "/sub/%2e%2e/%2e%2e/secret.txt",
+        "//../secret.txt",
+        "/outside.txt",
+        "/updir/secret.txt",
+        "/updir/",
+    ];
+    let mut responses = Vec::new();
+    for path in attempts {
+        let mut response = String::new();
+        get_path(path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        responses.push(response);
+    }
+    server.child.kill().unwrap();
+
+    for (path, response) in attempts.iter().zip(responses) {
+        assert!(
+            response.starts_with("HTTP/1.0 404 Not Found\r\n")
+                || response.starts_with("HTTP/1.0 403 Forbidden\r\n"),
+            "{path} was not blocked:\n{response}"
+        );
+        assert!(!response.contains("SECRET"), "{path} leaked the secret");
+    }
+}
+
+#[test]
+#[cfg(unix)]
+pub fn test_no_follow_symlinks() {
+    let root = temp_root("no_follow_symlinks");
+    std::fs::create_dir(root.join("sub")).unwrap();
+    std::fs::write(root.join("sub").join("real.txt"), "Real").unwrap();
+    std::os::unix::fs::symlink(root.join("sub").join("real.txt"), root.join("link.txt")).unwrap();
+    std::os::unix::fs::symlink(root.join("sub"), root.join("linkdir")).unwrap();
+
+    let mut responses = Vec::new();
+    for args in [&[][..], &["--no-follow-symlinks"][..]] {
+        let mut server = getserver(&[&["--root", root.to_str().unwrap()], args].concat());
+        for path in ["/sub/real.txt", "/link.txt", "/linkdir/real.txt", "/"] {
+            let mut response = String::new();
+            get_path(path, server.port)
+                .read_to_string(&mut response)
+                .unwrap();
+            responses.push(response);
+        }
+        server.child.kill().unwrap();
+    }
+
+    let (followed, refused) = responses.split_at(4);
+    for response in &followed[..3] {
+        assert!(response.ends_with("\r\n\r\nReal"));
+    }
+    assert!(followed[3].contains("link.txt"));
+    assert!(refused[0].ends_with("\r\n\r\nReal"));
+    assert!(refused[1].starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(refused[2].starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(!refused[3].contains("link.txt"));
+    assert!(!refused[3].contains("linkdir"));
+}
+
+#[test]
+#[cfg(unix)]
+pub fn test_no_follow_symlinks_error_page() {
+    let root = temp_root("no_follow_symlinks_error_page");
+    std::fs::create_dir(root.join("pages")).unwrap();
+    std::fs::write(root.join("pages").join("404.html"), "<h1>Linked</h1>").unwrap();
+    std::os::unix::fs::symlink(root.join("pages").join("404.html"), root.join("404.html")).unwrap();
+
+    let mut responses = Vec::new();
+    for args in [&[][..], &["--no-follow-symlinks"][..]] {
+        let server = start_server(&[&["--root", root.to_str().unwrap()], args].concat());
+        let mut response = String::new();
+        get_path("/missing", server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        responses.push(response);
+        server.stop();
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert!(responses[0].ends_with("\r\n\r\n<h1>Linked</h1>"));
+    assert!(responses[1].starts_with("HTTP/1.0 404 Not Found\r\n"));
+    assert!(!responses[1].contains("Linked"));
+}
+
+#[test]
+pub fn test_single_file() {
+    let root = temp_root("single_file");
+    let file = root.join("notes.txt");
+    std::fs::write(&file, "Just this").unwrap();
+    let server = start_server(&["--file", file.to_str().unwrap()]);
+
+    let responses: Vec<_> = ["/", "/notes.txt", "/some/other/path", "/Cargo.toml"]
+        .iter()
+        .map(|path| {
+            let mut response = String::new();
+            get_path(path, server.port)
+                .read_to_string(&mut response)
+                .unwrap();
+            response
+        })
+        .collect();
+    server.stop();
+
+    for response in responses {
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Type: text/plain"));
+        assert!(response.ends_with("\r\n\r\nJust this"));
+    }
+}
+
+#[test]
+pub fn test_check() {
+    let root = temp_root("check");
+    std::fs::write(root.join("cert.pem"), "not a certificate").unwrap();
+    let check = |args: &[&str]| {
+        let port = port_check::free_local_ipv4_port().unwrap().to_string();
+        Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+            .args(["127.0.0.1", &port, "--check"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let ok = check(&["--root", root.to_str().unwrap()]);
+    let missing_root = check(&["--root", root.join("missing").to_str().unwrap()]);
+    let bad_cert = check(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--cert",
+        root.join("cert.pem").to_str().unwrap(),
+        "--key",
+        root.join("cert.pem").to_str().unwrap(),
+    ]);
+
+    assert!(ok.status.success());
+    assert!(String::from_utf8_lossy(&ok.stdout).contains("Configuration OK."));
+    assert!(!missing_root.status.success());
+    assert!(!bad_cert.status.success());
+}
+
+#[cfg(unix)]
+#[test]
+pub fn test_check_unix_socket() {
+    let root = temp_root("check_unix_socket");
+    let socket = root.join("server.sock");
+    let check = Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+        .args(["--check", "--unix-socket", socket.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    assert!(check.status.success());
+    // Checking shouldn't leave anything behind that a real start would trip over
+    assert!(!socket.exists());
+}
+
+#[cfg(unix)]
+#[test]
+pub fn test_unix_socket() {
+    use std::os::unix::net::UnixStream;
+
+    let root = temp_root("unix_socket");
+    std::fs::write(root.join("index.html"), "Hello over a socket").unwrap();
+    let socket = root.join("server.sock");
+    let mut server = getserver(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--unix-socket",
+        socket.to_str().unwrap(),
+    ]);
+
+    let mut conn = UnixStream::connect(&socket).unwrap();
+    conn.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+    server.child.kill().unwrap();
+    server.child.wait().unwrap();
+
+    // A socket left behind by a killed server doesn't stop the next one
+    let mut server = getserver(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--unix-socket",
+        socket.to_str().unwrap(),
+    ]);
+    let mut conn = UnixStream::connect(&socket).unwrap();
+    conn.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let mut restarted = String::new();
+    conn.read_to_string(&mut restarted).unwrap();
+    server.child.kill().unwrap();
+
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nHello over a socket"));
+    assert!(restarted.ends_with("\r\n\r\nHello over a socket"));
+}
+
+#[cfg(unix)]
+#[test]
+pub fn test_world_writable_root() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = temp_root("world_writable_root");
+    std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o777)).unwrap();
+    let check = |args: &[&str]| {
+        let port = port_check::free_local_ipv4_port().unwrap().to_string();
+        Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+            .args([
+                "127.0.0.1",
+                &port,
+                "--check",
+                "--root",
+                root.to_str().unwrap(),
+            ])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let lenient = check(&[]);
+    let strict = check(&["--strict-permissions"]);
+
+    assert!(lenient.status.success());
+    assert!(String::from_utf8_lossy(&lenient.stdout).contains("is world-writable"));
+    assert!(!strict.status.success());
+    assert!(String::from_utf8_lossy(&strict.stderr).contains("Refusing to serve it"));
+}
+
+#[test]
+pub fn test_bind_failure() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port().to_string();
+    let output = Command::new(env!("CARGO_BIN_EXE_simplewebserver_rs"))
+        .args(["127.0.0.1", &port])
+        .output()
+        .unwrap();
+    let log = String::from_utf8_lossy(&output.stderr) + String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success());
+    assert!(log.contains(&format!(
+        "Failed to bind to 127.0.0.1:{port}: The address is already in use."
+    )));
+}
+
+#[test]
+pub fn test_quiet_errors() {
+    let mut server = getserver(&["--quiet-errors"]);
+    let (mut missing, mut not_allowed) = (String::new(), String::new());
+    get_path("/missing", server.port)
+        .read_to_string(&mut missing)
+        .unwrap();
+    request("POST", "/", server.port)
+        .read_to_string(&mut not_allowed)
+        .unwrap();
+    server.child.kill().unwrap();
+
+    for response in [missing, not_allowed] {
+        assert!(response.contains("\r\nContent-Length: 0\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+}
+
+#[test]
+pub fn test_if_range() {
+    let root = temp_root("if_range");
+    std::fs::write(root.join("digits.txt"), "0123456789").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let get = |headers: &[&str]| {
+        let mut response = String::new();
+        request_with_headers("GET", "/digits.txt", headers, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+    let full = get(&[]);
+    let header = |name: &str| {
+        full.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap()
+            .to_string()
+    };
+    let (etag, modified) = (header("ETag: "), header("Last-Modified: "));
+
+    let range = "Range: bytes=2-5";
+    let same_etag = get(&[range, &format!("If-Range: {etag}")]);
+    let same_date = get(&[range, &format!("If-Range: {modified}")]);
+    let other_etag = get(&[range, "If-Range: \"something-else\""]);
+    let weak_etag = get(&[range, &format!("If-Range: W/{etag}")]);
+    let old_date = get(&[range, "If-Range: Sat, 01 Jan 2000 00:00:00 GMT"]);
+
+    server.stop();
+
+    for response in [same_etag, same_date] {
+        assert!(response.starts_with("HTTP/1.0 206 Partial Content\r\n"));
+        assert!(response.ends_with("\r\n\r\n2345"));
+    }
+    // The file changed, or we can't tell, so the client gets all of it again
+    for response in [other_etag, weak_etag, old_date] {
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n0123456789"));
+    }
+}
+
+#[test]
+pub fn test_cache_validation_with_ranges() {
+    let root = temp_root("cache_validation");
+    let file = root.join("digits.txt");
+    std::fs::write(&file, "0123456789").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let get = |headers: &[&str]| {
+        let mut response = String::new();
+        request_with_headers("GET", "/digits.txt", headers, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+    let first = get(&[]);
+    let etag = first
+        .lines()
+        .find_map(|line| line.strip_prefix("ETag: "))
+        .unwrap()
+        .to_string();
+    let if_none_match = format!("If-None-Match: {etag}");
+    let if_range = format!("If-Range: {etag}");
+
+    let revalidated = get(&[&if_none_match]);
+    let partial = get(&["Range: bytes=2-5", &if_range]);
+    // A client with a fresh copy doesn't need any part of it
+    let revalidated_range = get(&["Range: bytes=2-5", &if_none_match]);
+
+    // Different length, so a different ETag
+    std::fs::write(&file, "abcdefghijklmnop").unwrap();
+    let changed = get(&[&if_none_match]);
+    let stale_range = get(&["Range: bytes=2-5", &if_range]);
+
+    server.stop();
+
+    assert!(first.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(revalidated.starts_with("HTTP/1.0 304 Not Modified\r\n"));
+    assert!(revalidated.ends_with("\r\n\r\n"));
+    assert!(partial.starts_with("HTTP/1.0 206 Partial Content\r\n"));
+    assert!(partial.contains("\r\nContent-Range: bytes 2-5/10\r\n"));
+    assert!(partial.ends_with("\r\n\r\n2345"));
+    assert!(revalidated_range.starts_with("HTTP/1.0 304 Not Modified\r\n"));
+    assert!(changed.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(!changed.contains(&format!("ETag: {etag}\r\n")));
+    assert!(stale_range.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(!stale_range.contains("\r\nContent-Range: "));
+    assert!(stale_range.ends_with("\r\n\r\nabcdefghijklmnop"));
+}
+
+#[test]
+pub fn test_logfile_paths() {
+    let root = temp_root("logfile_paths");
+    let (logfile, full_logfile) = (root.join("server.log"), root.join("logs-full.log"));
+    let mut server = getserver(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--enablelogfiles",
+        "--logfile",
+        logfile.to_str().unwrap(),
+        "--full-logfile",
+        full_logfile.to_str().unwrap(),
+    ]);
+
+    let mut responses = Vec::new();
+    for path in ["/server.log", "/logs-full.log"] {
+        let mut response = String::new();
+        get_path(path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        responses.push(response);
+    }
+    server.child.kill().unwrap();
+
+    // Moved log files are still kept from being served
+    for response in responses {
+        assert!(response.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    }
+    assert!(
+        std::fs::read_to_string(&logfile)
+            .unwrap()
+            .contains("Serving on")
+    );
+    assert!(full_logfile.exists());
+}
+
+#[test]
+pub fn test_logs_appended() {
+    let root = temp_root("logs_appended");
+    let logfile = root.join("server.log");
+    let full_logfile = root.join("server-full.log");
+    let args = [
+        "--root",
+        root.to_str().unwrap(),
+        "--enablelogfiles",
+        "--logfile",
+        logfile.to_str().unwrap(),
+        "--full-logfile",
+        full_logfile.to_str().unwrap(),
+    ];
+
+    // Startup is logged to the terminal first, so wait for a response to know the file has it too
+    let run = |args: &[&str]| {
+        let server = getserver(args);
+        get_path("/", server.port)
+            .read_to_string(&mut String::new())
+            .unwrap();
+        server.stop();
+        std::fs::read_to_string(&logfile).unwrap()
+    };
+
+    std::fs::write(&logfile, "Previous run\n").unwrap();
+    let appended = run(&args);
+    let truncated = run(&[&args[..], &["--truncate-logs"]].concat());
+
+    assert!(appended.starts_with("Previous run\n"));
+    assert!(appended.contains("Serving on"));
+    assert!(!truncated.contains("Previous run"));
+    assert!(truncated.contains("Serving on"));
+}
+
+#[test]
+pub fn test_connection_ids() {
+    let root = temp_root("connection_ids");
+    let logfile = root.join("server.log");
+    let full_logfile = root.join("server-full.log");
+    let mut server = getserver(&[
+        "--root",
+        root.to_str().unwrap(),
+        "--enablelogfiles",
+        "--logfile",
+        logfile.to_str().unwrap(),
+        "--full-logfile",
+        full_logfile.to_str().unwrap(),
+    ]);
+    for path in ["/first", "/second"] {
+        let mut response = String::new();
+        get_path(path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+    }
+    server.child.kill().unwrap();
+    server.child.wait().unwrap();
+
+    let log = std::fs::read_to_string(&logfile).unwrap();
+    let id_of = |path: &str| {
+        let line = log
+            .lines()
+            .find(|line| line.ends_with(&format!("GET {path} - 404")))
+            .unwrap();
+        let id = line.split("[#").nth(1).unwrap().split(']').next().unwrap();
+        id.parse::<u64>().unwrap()
+    };
+    // Every connection gets its own
+    assert_ne!(id_of("/first"), id_of("/second"));
+    // Startup isn't part of any connection
+    assert!(
+        log.lines()
+            .filter(|line| line.contains("Serving on"))
+            .all(|line| !line.contains("[#"))
+    );
+}
+
+#[test]
+pub fn test_precompressed() {
+    let root = temp_root("precompressed");
+    std::fs::write(root.join("style.css"), "body {}").unwrap();
+    std::fs::write(root.join("style.css.gz"), "gzipped").unwrap();
+    std::fs::write(root.join("style.css.br"), "brotlied").unwrap();
+    std::fs::write(root.join("only-gz.js"), "plain").unwrap();
+    std::fs::write(root.join("only-gz.js.gz"), "gzipped js").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+
+    let get = |path: &str, headers: &[&str]| {
+        let mut response = String::new();
+        request_with_headers("GET", path, headers, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+        response
+    };
+    let plain = get("/style.css", &[]);
+    let gzip = get("/style.css", &["Accept-Encoding: gzip"]);
+    let brotli = get("/style.css", &["Accept-Encoding: gzip, br"]);
+    let refused = get("/style.css", &["Accept-Encoding: br;q=0, gzip"]);
+    let range = get("/style.css", &["Accept-Encoding: gzip", "Range: bytes=0-3"]);
+    let fallback = get("/only-gz.js", &["Accept-Encoding: br"]);
+
+    server.stop();
+
+    assert!(plain.ends_with("\r\n\r\nbody {}"));
+    assert!(!plain.contains("Content-Encoding"));
+    assert!(gzip.contains("\r\nContent-Encoding: gzip\r\n"));
+    assert!(gzip.contains("\r\nContent-Type: text/css"));
+    assert!(gzip.contains("\r\nVary: Accept-Encoding\r\n"));
+    assert!(gzip.ends_with("\r\n\r\ngzipped"));
+    assert!(brotli.contains("\r\nContent-Encoding: br\r\n"));
+    assert!(brotli.ends_with("\r\n\r\nbrotlied"));
+    assert!(refused.ends_with("\r\n\r\ngzipped"));
+    assert!(range.starts_with("HTTP/1.0 206 Partial Content\r\n"));
+    assert!(range.ends_with("\r\n\r\nbody"));
+    assert!(fallback.ends_with("\r\n\r\nplain"));
+    // Each version gets its own ETag
+    let etag = |response: &str| {
+        response
+            .lines()
+            .find(|line| line.starts_with("ETag: "))
+            .unwrap()
+            .to_string()
+    };
+    assert_ne!(etag(&plain), etag(&gzip));
+    assert_ne!(etag(&gzip), etag(&brotli));
+}
+
+#[test]
+pub fn test_listing_order_and_details() {
+    let root = temp_root("listing_order");
+    std::fs::write(root.join("b.txt"), vec![b'b'; 2048]).unwrap();
+    std::fs::write(root.join("A.txt"), "a").unwrap();
+    std::fs::create_dir(root.join("zdir")).unwrap();
+    std::fs::create_dir(root.join("adir")).unwrap();
+
+    let mut listings = Vec::new();
+    for args in [&[][..], &["--listing-details"][..]] {
+        let server = start_server(&[&["--root", root.to_str().unwrap()], args].concat());
+        let mut listing = String::new();
+        get_path("/", server.port)
+            .read_to_string(&mut listing)
+            .unwrap();
+        server.stop();
+        listings.push(listing);
+    }
+
+    // Directories first, then by name regardless of case
+    let position = |name: &str| listings[0].find(&format!(">{name}<")).unwrap();
+    assert!(position("adir/") < position("zdir/"));
+    assert!(position("zdir/") < position("A.txt"));
+    assert!(position("A.txt") < position("b.txt"));
+    assert!(!listings[0].contains("<span>"));
+    assert!(listings[1].contains(">b.txt</a><span>2.0 KiB</span><span>"));
+    assert!(listings[1].contains(">A.txt</a><span>1 B</span><span>"));
+    assert!(listings[1].contains(">adir/</a><span>-</span><span>"));
+}
+
+#[test]
+pub fn test_listing_marks_directories() {
+    let root = temp_root("listing_marks_directories");
+    std::fs::create_dir(root.join("folder")).unwrap();
+    std::fs::write(root.join("file.txt"), "").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap()]);
+    let mut listing = String::new();
+    get_path("/", server.port)
+        .read_to_string(&mut listing)
+        .unwrap();
+    server.stop();
+
+    assert!(listing.contains("<li class=\"dir\"><a href=\"/folder/\">folder/</a></li>"));
+    assert!(listing.contains("<li class=\"file\"><a href=\"/file.txt\">file.txt</a></li>"));
+}
+
+#[test]
+pub fn test_metrics() {
+    let root = temp_root("metrics");
+    std::fs::write(root.join("page.html"), "Hello").unwrap();
+    let server = start_server(&["--root", root.to_str().unwrap(), "--metrics-path"]);
+    for path in ["/page.html", "/missing"] {
+        let mut response = String::new();
+        get_path(path, server.port)
+            .read_to_string(&mut response)
+            .unwrap();
+    }
+    // Give the workers a moment to finish up the closed connections
+    thread::sleep(Duration::from_millis(100));
+    let mut metrics = String::new();
+    get_path("/metrics", server.port)
+        .read_to_string(&mut metrics)
+        .unwrap();
+    server.stop();
+
+    assert!(metrics.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(metrics.contains("\r\n# HELP simplewebserver_requests_total "));
+    assert!(metrics.contains("\n# TYPE simplewebserver_requests_total counter\n"));
+    // Including the metrics request itself, which hasn't been answered yet
+    assert!(metrics.contains("\nsimplewebserver_requests_total 3\n"));
+    assert!(metrics.contains("\nsimplewebserver_responses_total{status=\"200\"} 1\n"));
+    assert!(metrics.contains("\nsimplewebserver_responses_total{status=\"404\"} 1\n"));
+    assert!(metrics.contains("\nsimplewebserver_sent_bytes_total 9\n"));
+    assert!(metrics.contains("\nsimplewebserver_active_connections 1\n"));
+    assert!(metrics.contains(
+        "\n# HELP simplewebserver_ratelimited_total Requests turned away by the rate limiter.\n"
+    ));
+    assert!(metrics.contains("\nsimplewebserver_ratelimited_total 0\n"));
 }